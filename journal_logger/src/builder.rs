@@ -0,0 +1,79 @@
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+use crate::logger::{JournalLogger, SendErrorCallback, JOURNAL_SOCKET};
+use crate::priority::PriorityMap;
+
+/// Configures and creates a JournalLogger.
+pub struct Builder {
+	config: Config,
+	priorities: PriorityMap,
+	on_send_error: Option<SendErrorCallback>,
+	identifier: Option<String>,
+	mirror_stderr: bool,
+	fallback_stderr: bool,
+	socket_path: PathBuf
+}
+
+impl Default for Builder {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl Builder {
+	/// Creates a builder logging everything up to the level info to journald.
+	pub fn new() -> Self {
+		Self {
+			config: Config::new(),
+			priorities: PriorityMap::default(),
+			on_send_error: None,
+			identifier: None,
+			mirror_stderr: false,
+			fallback_stderr: false,
+			socket_path: PathBuf::from(JOURNAL_SOCKET)
+		}
+	}
+
+	config_methods!();
+	send_methods!();
+
+	/// Sets the SYSLOG_IDENTIFIER of all entries. By default the target of the record is used.
+	pub fn identifier(mut self, identifier: &str) -> Self {
+		self.identifier = Some(String::from(identifier));
+		self
+	}
+
+	/// Writes every entry to stderr too.
+	pub fn mirror_stderr(mut self, mirror: bool) -> Self {
+		self.mirror_stderr = mirror;
+		self
+	}
+
+	/// Writes entries to stderr that could not be sent to the journal.
+	pub fn fallback_stderr(mut self, fallback: bool) -> Self {
+		self.fallback_stderr = fallback;
+		self
+	}
+
+	/// Sends the entries to a different socket than the one of journald.
+	pub fn socket(mut self, socket_path: &Path) -> Self {
+		self.socket_path = PathBuf::from(socket_path);
+		self
+	}
+
+	pub fn build(self) -> JournalLogger {
+		JournalLogger {
+			filter: self.config.filter,
+			priorities: self.priorities,
+			on_send_error: self.on_send_error,
+			identifier: self.identifier,
+			mirror_stderr: self.mirror_stderr,
+			fallback_stderr: self.fallback_stderr,
+			socket_path: self.socket_path,
+			socket: UnixDatagram::unbound().ok(),
+			fields: self.config.fields
+		}
+	}
+}
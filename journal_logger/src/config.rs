@@ -0,0 +1,91 @@
+use log::LevelFilter;
+
+use crate::filter::Filter;
+use crate::logger::field_name;
+
+/// Configuration shared by the builders of all loggers. The builders get the methods
+/// configuring it from the config_methods macro.
+pub(crate) struct Config {
+	pub(crate) filter: Filter,
+	pub(crate) fields: Vec<(String, String)>
+}
+
+impl Config {
+	/// Logs everything up to the level info without static fields.
+	pub(crate) fn new() -> Self {
+		Self {
+			filter: Filter::new(LevelFilter::Info),
+			fields: Vec::new()
+		}
+	}
+
+	pub(crate) fn field(&mut self, key: &str, value: &str) {
+		if let Some(name) = field_name(key) {
+			self.fields.push((name, String::from(value)));
+		}
+	}
+}
+
+/// Implements the methods configuring the filter and the static fields and init. The
+/// builder needs a `config: Config` field and a build method. The logger needs a
+/// `filter` field and must implement Prepare.
+macro_rules! config_methods {
+	() => {
+		/// Sets the level of all modules without their own level.
+		pub fn level(mut self, level: ::log::LevelFilter) -> Self {
+			self.config.filter.add(None, level);
+			self
+		}
+
+		/// Sets the level of a module and its submodules.
+		pub fn filter(mut self, module: &str, level: ::log::LevelFilter) -> Self {
+			self.config.filter.add(Some(module), level);
+			self
+		}
+
+		/// Adds filter directives in the syntax of env_logger (e.g. `warn,tsos::system=debug`).
+		pub fn parse(mut self, directives: &str) -> Self {
+			self.config.filter.parse(directives);
+			self
+		}
+
+		/// Adds a field that is written with every record. The key is converted like the
+		/// keys of the key-values of a record.
+		pub fn field(mut self, key: &str, value: &str) -> Self {
+			self.config.field(key, value);
+			self
+		}
+
+		/// Creates the logger and installs it as the global logger. Records for it can be
+		/// prepared by `journal_logger::prepare`.
+		pub fn init(self) -> Result<(), ::log::SetLoggerError> {
+			let logger = Box::leak(Box::new(self.build()));
+			let max_level = logger.filter.max_level();
+
+			::log::set_logger(logger)?;
+			::log::set_max_level(max_level);
+			$crate::prepared::set_installed(logger);
+
+			Ok(())
+		}
+	};
+}
+
+/// Implements the methods of loggers sending records to a socket. The builder needs
+/// the fields `priorities: PriorityMap` and `on_send_error: Option<SendErrorCallback>`.
+macro_rules! send_methods {
+	() => {
+		/// Sets the syslog priority records of the level are sent with. A `priority`
+		/// key-value of a record takes precedence.
+		pub fn priority(mut self, level: ::log::Level, priority: $crate::Priority) -> Self {
+			self.priorities.set(level, priority);
+			self
+		}
+
+		/// Calls the callback if a record could not be sent.
+		pub fn on_send_error<F: Fn(&::std::io::Error) + Send + Sync + 'static>(mut self, callback: F) -> Self {
+			self.on_send_error = Some(Box::new(callback));
+			self
+		}
+	};
+}
@@ -0,0 +1,92 @@
+use log::{Level, LevelFilter};
+use std::str::FromStr;
+
+/// Level of a module and its submodules. A directive without a module sets the level
+/// of all modules without a directive.
+struct Directive {
+	module: Option<String>,
+	level: LevelFilter
+}
+
+/// Filters records by their target. Uses the same directive syntax as env_logger
+/// (e.g. `warn,tsos::system=debug`).
+pub struct Filter {
+	directives: Vec<Directive>
+}
+
+impl Filter {
+	pub fn new(level: LevelFilter) -> Self {
+		Self {
+			directives: vec![ Directive { module: None, level } ]
+		}
+	}
+
+	/// Sets the level of a module. If module is None, the default level is set.
+	pub fn add(&mut self, module: Option<&str>, level: LevelFilter) {
+		let module = module.map(String::from);
+		self.directives.retain(|v| v.module != module);
+		self.directives.push(Directive { module, level });
+	}
+
+	/// Adds the directives of a comma separated list. A directive is either a level,
+	/// a module name (enabling all levels) or `module=level`. Invalid levels are ignored.
+	pub fn parse(&mut self, directives: &str) {
+		for directive in directives.split(',').map(|v| v.trim()).filter(|v| !v.is_empty()) {
+			match directive.split_once('=') {
+				Some((module, level)) => if let Ok(level) = LevelFilter::from_str(level.trim()) {
+					self.add(Some(module.trim()), level);
+				},
+				None => match LevelFilter::from_str(directive) {
+					Ok(level) => self.add(None, level),
+					Err(_) => self.add(Some(directive), LevelFilter::Trace)
+				}
+			}
+		}
+	}
+
+	/// Returns true if the module matches the target. Submodules of the module match too.
+	fn matches(module: &str, target: &str) -> bool {
+		target.strip_prefix(module).map(|v| v.is_empty() || v.starts_with("::")).unwrap_or(false)
+	}
+
+	/// The level of the most specific directive matching the target.
+	fn level(&self, target: &str) -> LevelFilter {
+		self.directives.iter()
+			.filter(|v| v.module.as_deref().map(|v| Self::matches(v, target)).unwrap_or(true))
+			.max_by_key(|v| v.module.as_ref().map(|v| v.len() + 1).unwrap_or(0))
+			.map(|v| v.level)
+			.unwrap_or(LevelFilter::Off)
+	}
+
+	pub fn enabled(&self, target: &str, level: Level) -> bool {
+		level <= self.level(target)
+	}
+
+	/// The most verbose level of all directives.
+	pub fn max_level(&self) -> LevelFilter {
+		self.directives.iter().map(|v| v.level).max().unwrap_or(LevelFilter::Off)
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	/// Verify that the most specific directive is used.
+	#[test]
+	fn directives() {
+		let mut filter = Filter::new(LevelFilter::Info);
+		filter.parse("warn, tsos::system=debug, tsos::audit, invalid=loud");
+
+		assert!(filter.enabled("tsos", Level::Warn));
+		assert!(!filter.enabled("tsos", Level::Info));
+		assert!(filter.enabled("tsos::system", Level::Debug));
+		assert!(filter.enabled("tsos::system::ramfs", Level::Debug));
+		assert!(!filter.enabled("tsos::system", Level::Trace));
+		assert!(!filter.enabled("tsos::systemd", Level::Info));
+		assert!(filter.enabled("tsos::audit", Level::Trace));
+		assert!(!filter.enabled("invalid", Level::Info));
+		assert_eq!(filter.max_level(), LevelFilter::Trace);
+	}
+}
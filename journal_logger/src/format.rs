@@ -0,0 +1,93 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Converts the days since the epoch into year, month and day (proleptic Gregorian calendar).
+pub(crate) fn civil_date(days: i64) -> (i64, i64, i64) {
+	let z = days + 719468;
+	let era = z.div_euclid(146097);
+	let doe = z.rem_euclid(146097);
+	let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+	let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+	let mp = (5 * doy + 2) / 153;
+	let day = doy - (153 * mp + 2) / 5 + 1;
+	let month = if mp < 10 { mp + 3 } else { mp - 9 };
+
+	(yoe + era * 400 + if month <= 2 { 1 } else { 0 }, month, day)
+}
+
+/// Length of the timestamps written by write_rfc3339_time.
+pub(crate) const RFC3339_LENGTH: usize = 27;
+
+/// Writes the value as decimal digits padded with zeros into the whole buffer.
+pub(crate) fn write_digits(out: &mut [u8], mut value: u64) {
+	for digit in out.iter_mut().rev() {
+		*digit = b'0' + (value % 10) as u8;
+		value /= 10;
+	}
+}
+
+/// Writes the time as an RFC 3339 timestamp in UTC with microseconds into the first
+/// RFC3339_LENGTH bytes of the buffer. Does not allocate memory. Therefore it can be
+/// used to fill in the time of prepared records.
+pub(crate) fn write_rfc3339_time(time: SystemTime, out: &mut [u8]) {
+	let duration = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+	let seconds = duration.as_secs();
+	let (year, month, day) = civil_date((seconds / 86400) as i64);
+	let seconds = seconds % 86400;
+
+	out[..RFC3339_LENGTH].copy_from_slice(b"0000-00-00T00:00:00.000000Z");
+	write_digits(&mut out[0..4], year as u64);
+	write_digits(&mut out[5..7], month as u64);
+	write_digits(&mut out[8..10], day as u64);
+	write_digits(&mut out[11..13], seconds / 3600);
+	write_digits(&mut out[14..16], seconds / 60 % 60);
+	write_digits(&mut out[17..19], seconds % 60);
+	write_digits(&mut out[20..26], duration.subsec_micros() as u64);
+}
+
+/// Formats the time as an RFC 3339 timestamp in UTC with microseconds.
+pub fn rfc3339_time(time: SystemTime) -> String {
+	let mut buffer = [0u8; RFC3339_LENGTH];
+	write_rfc3339_time(time, &mut buffer);
+
+	String::from_utf8_lossy(&buffer).into_owned()
+}
+
+/// Writes the string as a quoted JSON string.
+pub fn write_json_string(out: &mut String, value: &str) {
+	out.push('"');
+	for c in value.chars() {
+		match c {
+			'"' => out.push_str("\\\""),
+			'\\' => out.push_str("\\\\"),
+			'\n' => out.push_str("\\n"),
+			'\r' => out.push_str("\\r"),
+			'\t' => out.push_str("\\t"),
+			c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+			c => out.push(c)
+		}
+	}
+	out.push('"');
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use std::time::Duration;
+
+	/// Verify the timestamps around the epoch and a leap day.
+	#[test]
+	fn timestamps() {
+		assert_eq!(rfc3339_time(UNIX_EPOCH), "1970-01-01T00:00:00.000000Z");
+		assert_eq!(rfc3339_time(UNIX_EPOCH + Duration::new(951782400, 500)), "2000-02-29T00:00:00.000000Z");
+		assert_eq!(rfc3339_time(UNIX_EPOCH + Duration::new(1792366262, 123456000)), "2026-10-18T23:31:02.123456Z");
+	}
+
+	/// Verify that quotes and control characters are escaped.
+	#[test]
+	fn json_strings() {
+		let mut out = String::new();
+		write_json_string(&mut out, "\"a\\b\"\n\u{1}");
+		assert_eq!(out, "\"\\\"a\\\\b\\\"\\n\\u0001\"");
+	}
+}
@@ -0,0 +1,144 @@
+use log::{Record, Metadata};
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::Config;
+use crate::filter::Filter;
+use crate::logger::FieldCollector;
+use crate::format::{rfc3339_time, write_json_string};
+use crate::prepared::{Prepare, PreparedRecord, TimeFormat};
+
+/// Offset of the timestamp within a line.
+const TIME_OFFSET: usize = "{\"timestamp\":\"".len();
+
+/// Formats a record as a single JSON object. The fields follow the timestamp, level,
+/// target and message.
+fn to_json_line(time: SystemTime, level: &str, target: &str, message: &str, fields: &[(String, String)]) -> String {
+	let timestamp = rfc3339_time(time);
+	let header = [ ("timestamp", timestamp.as_str()), ("level", level), ("target", target), ("message", message) ];
+
+	let mut line = String::from("{");
+	for (key, value) in header.iter().copied().chain(fields.iter().map(|(k, v)| (k.as_str(), v.as_str()))) {
+		if line.len() > 1 { line.push(','); }
+		write_json_string(&mut line, key);
+		line.push(':');
+		write_json_string(&mut line, value);
+	}
+	line.push_str("}\n");
+
+	line
+}
+
+/// Implements a logger writing one JSON object per record to stderr. The fields carry
+/// the same names as within the journal. Use the JsonBuilder to create it.
+pub struct JsonLogger {
+	filter: Filter,
+	out: Mutex<Box<dyn Write + Send>>,
+	/// Set if out is stderr. Only lines for stderr can be prepared.
+	stderr: bool,
+	fields: Vec<(String, String)>
+}
+
+impl JsonLogger {
+	/// Formats the record with its fields followed by the static fields.
+	fn line(&self, time: SystemTime, record: &Record) -> String {
+		let mut fields = Vec::new();
+		let _ = record.key_values().visit(&mut FieldCollector { fields: &mut fields });
+		fields.extend(self.fields.iter().cloned());
+
+		to_json_line(time, record.level().as_str(), record.target(), &record.args().to_string(), &fields)
+	}
+}
+
+/// Prepared lines are written to stderr directly. The lock of the writer is not used.
+impl Prepare for JsonLogger {
+	fn prepare(&self, record: &Record) -> Option<PreparedRecord> {
+		if !self.stderr || !log::Log::enabled(self, record.metadata()) { return None; }
+
+		Some(PreparedRecord::line(self.line(UNIX_EPOCH, record).into_bytes(), Some((TIME_OFFSET, TimeFormat::Rfc3339))))
+	}
+}
+
+impl log::Log for JsonLogger {
+	fn enabled(&self, metadata: &Metadata) -> bool {
+		self.filter.enabled(metadata.target(), metadata.level())
+	}
+
+	fn log(&self, record: &Record) {
+		if self.enabled(record.metadata()) {
+			let line = self.line(SystemTime::now(), record);
+			// The line is written at once. That way lines of concurrent records are not mixed.
+			if let Ok(mut out) = self.out.lock() {
+				let _ = out.write_all(line.as_bytes());
+			}
+		}
+	}
+
+	fn flush(&self) {
+		if let Ok(mut out) = self.out.lock() {
+			let _ = out.flush();
+		}
+	}
+}
+
+/// Configures and creates a JsonLogger.
+pub struct JsonBuilder {
+	config: Config,
+	out: Box<dyn Write + Send>,
+	stderr: bool
+}
+
+impl Default for JsonBuilder {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl JsonBuilder {
+	/// Creates a builder logging everything up to the level info to stderr.
+	pub fn new() -> Self {
+		Self {
+			config: Config::new(),
+			out: Box::new(io::stderr()),
+			stderr: true
+		}
+	}
+
+	config_methods!();
+
+	/// Writes the lines to a different output than stderr.
+	pub fn writer<W: Write + Send + 'static>(mut self, out: W) -> Self {
+		self.out = Box::new(out);
+		self.stderr = false;
+		self
+	}
+
+	pub fn build(self) -> JsonLogger {
+		JsonLogger {
+			filter: self.config.filter,
+			out: Mutex::new(self.out),
+			stderr: self.stderr,
+			fields: self.config.fields
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use std::time::Duration;
+
+	/// Verify that a record is formatted as a single JSON object.
+	#[test]
+	fn json_lines() {
+		let fields = [ (String::from("TSOS_EXEC"), String::from("/usr/bin/app")) ];
+		assert_eq!(to_json_line(UNIX_EPOCH + Duration::new(1792366262, 0), "ERROR", "tsos", "\"q\"\n\u{1}", &fields),
+			"{\"timestamp\":\"2026-10-18T23:31:02.000000Z\",\"level\":\"ERROR\",\"target\":\"tsos\",\"message\":\"\\\"q\\\"\\n\\u0001\",\"TSOS_EXEC\":\"/usr/bin/app\"}\n");
+
+		// The timestamp of prepared lines is overwritten when they are written.
+		let line = to_json_line(UNIX_EPOCH, "ERROR", "tsos", "m", &[]);
+		assert_eq!(&line[TIME_OFFSET..TIME_OFFSET + 27], "1970-01-01T00:00:00.000000Z");
+	}
+}
@@ -1,13 +1,39 @@
 use std::env;
-use libc;
 use std::os::unix::io::{RawFd, AsRawFd};
 use std::mem::MaybeUninit;
 use std::io::{stdout, stderr};
 
+#[cfg(target_os = "linux")]
+#[macro_use]
+mod config;
 #[cfg(target_os = "linux")]
 mod logger;
 #[cfg(target_os = "linux")]
-pub use logger::init_with_level;
+mod filter;
+#[cfg(target_os = "linux")]
+mod builder;
+#[cfg(target_os = "linux")]
+mod syslog;
+#[cfg(target_os = "linux")]
+mod json;
+#[cfg(target_os = "linux")]
+mod priority;
+#[cfg(target_os = "linux")]
+mod prepared;
+mod format;
+#[cfg(target_os = "linux")]
+pub use logger::{init_with_level, init_with_fields, init_with_socket, failed_sends, JournalLogger, SendErrorCallback, JOURNAL_SOCKET};
+#[cfg(target_os = "linux")]
+pub use prepared::{prepare, Prepare, PreparedRecord};
+#[cfg(target_os = "linux")]
+pub use priority::{Priority, PriorityMap, ParsePriorityError};
+pub use format::{rfc3339_time, write_json_string};
+#[cfg(target_os = "linux")]
+pub use builder::Builder;
+#[cfg(target_os = "linux")]
+pub use json::{JsonBuilder, JsonLogger};
+#[cfg(target_os = "linux")]
+pub use syslog::{SyslogBuilder, SyslogLogger, SyslogFormat, Facility, SYSLOG_SOCKET, STRUCTURED_DATA_ID};
 
 #[cfg(not(target_os = "linux"))]
 pub fn init_with_level(level: Level) -> Result<(), SetLoggerError> {
@@ -37,7 +63,7 @@ fn check_descriptor(device: libc::dev_t, inode: libc::ino_t, fd: RawFd) -> bool{
 #[cfg(target_os = "linux")]
 pub fn has_journal() -> bool {
 	if let Ok(journal_stream) = env::var("JOURNAL_STREAM") {
-		if let [Some(device), Some(inode)] = journal_stream.split(':').map(|v| u64::from_str_radix(v.trim(), 10).ok()).collect::<Vec<Option<u64>>>()[0..2] {
+		if let [Some(device), Some(inode)] = journal_stream.split(':').map(|v| v.trim().parse::<u64>().ok()).collect::<Vec<Option<u64>>>()[0..2] {
 			// Check if stderr or stdout match the passed device and inode number
 			check_descriptor(device, inode, stderr().as_raw_fd()) ||
 			check_descriptor(device, inode, stdout().as_raw_fd())
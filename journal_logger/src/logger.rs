@@ -1,74 +1,233 @@
 use log::{Record, Level, Metadata, SetLoggerError};
-use libc;
+use log::kv::{Key, Value, VisitSource};
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{self, Write};
+use std::mem::{self, size_of};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-#[repr(C)]
-struct iovec {
-    pub iov_base: *const libc::c_void,
-    pub iov_len: libc::size_t,
+use crate::builder::Builder;
+use crate::filter::Filter;
+use crate::priority::{PriorityMap, priority_override};
+use crate::prepared::{plain_line, socket_address, Prepare, PreparedRecord};
+
+/// Socket journald receives messages of the native protocol on.
+pub const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Number of entries that could not be sent to the journal or syslog.
+pub(crate) static FAILED_SENDS: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the number of entries that could not be sent to the journal or syslog by
+/// any logger of this process.
+pub fn failed_sends() -> usize {
+	FAILED_SENDS.load(Ordering::Relaxed)
+}
+
+/// Called with the error if an entry could not be sent to the journal or syslog.
+pub type SendErrorCallback = Box<dyn Fn(&io::Error) + Send + Sync>;
+
+/// Name of the key-value overriding the priority of an entry.
+pub(crate) const PRIORITY_KEY: &str = "PRIORITY";
+
+/// Field name of priority key-values whose value is not a valid priority. PRIORITY
+/// itself is reserved for the priority of the entry.
+pub(crate) const INVALID_PRIORITY_KEY: &str = "INVALID_PRIORITY";
+
+/// Maximum length of a journal field name.
+const MAX_FIELD_NAME: usize = 64;
+
+/// Converts a key into a valid journal field name. Field names may only contain upper
+/// case letters, digits and underscores and must start with a letter. Leading
+/// underscores are removed because these fields are reserved for journald. Other
+/// invalid characters are replaced by underscores. Returns None if nothing is left.
+pub(crate) fn field_name(key: &str) -> Option<String> {
+	let name: String = key.chars().map(|c| match c {
+		'a'..='z' => c.to_ascii_uppercase(),
+		'A'..='Z' | '0'..='9' | '_' => c,
+		_ => '_'
+	}).collect();
+	let mut name = String::from(name.trim_start_matches('_'));
+
+	if name.starts_with(|c: char| c.is_ascii_digit()) {
+		name.insert(0, 'F');
+	}
+	name.truncate(MAX_FIELD_NAME);
+
+	if name.is_empty() { None } else { Some(name) }
+}
+
+/// Collects the key-values of a record as journal fields. Keys that can not be
+/// converted into a field name are skipped.
+pub(crate) struct FieldCollector<'a> {
+	pub(crate) fields: &'a mut Vec<(String, String)>
+}
+
+impl<'a, 'kvs> VisitSource<'kvs> for FieldCollector<'a> {
+	fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+		// The priority key-value overrides the priority of the entry and is not a field.
+		if priority_override(&key, &value).is_some() { return Ok(()); }
+		match field_name(key.as_str()) {
+			Some(ref name) if name == PRIORITY_KEY => self.fields.push((String::from(INVALID_PRIORITY_KEY), value.to_string())),
+			Some(name) => self.fields.push((name, value.to_string())),
+			None => ()
+		}
+		Ok(())
+	}
 }
 
-#[link(name = "systemd")]
-extern "C" {
-	fn sd_journal_sendv(iv: *const iovec, n: libc::c_int) -> libc::c_int;
+/// Appends a field in the format of the native journal protocol. Values containing a
+/// newline are prefixed with their length.
+fn append_field(buffer: &mut Vec<u8>, key: &str, value: &[u8]) {
+	buffer.extend_from_slice(key.as_bytes());
+	if value.contains(&b'\n') {
+		buffer.push(b'\n');
+		buffer.extend_from_slice(&(value.len() as u64).to_le_bytes());
+	} else {
+		buffer.push(b'=');
+	}
+	buffer.extend_from_slice(value);
+	buffer.push(b'\n');
 }
 
-/* from syslog.h */
-#[repr(u8)]
-enum Priority {
-	//Emerg =   0, /* system is unusable */
-	//Alert =   1, /* action must be taken immediately */
-	//Crit =    2, /* critical conditions */
-	Error =   3,   /* error conditions */
-	Warning = 4,   /* warning conditions */
-	//Notice =  5, /* normal but significant condition */
-	Info =    6,   /* informational */
-	Debug =   7    /* debug-level messages */
+/// Sends the file descriptor to the socket without any payload.
+fn send_fd(socket: &UnixDatagram, socket_path: &Path, fd: RawFd) -> io::Result<()> {
+	let mut address = socket_address(socket_path)?;
+
+	// Use u64 to get a buffer that is aligned for cmsghdr.
+	let control_len = unsafe { libc::CMSG_SPACE(size_of::<RawFd>() as u32) } as usize;
+	let mut control = vec![0u64; control_len.div_ceil(size_of::<u64>())];
+
+	let mut message: libc::msghdr = unsafe { mem::zeroed() };
+	message.msg_name = &mut address as *mut libc::sockaddr_un as *mut libc::c_void;
+	message.msg_namelen = size_of::<libc::sockaddr_un>() as libc::socklen_t;
+	message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+	message.msg_controllen = control_len as _;
+
+	unsafe {
+		let header = libc::CMSG_FIRSTHDR(&message);
+		(*header).cmsg_level = libc::SOL_SOCKET;
+		(*header).cmsg_type = libc::SCM_RIGHTS;
+		(*header).cmsg_len = libc::CMSG_LEN(size_of::<RawFd>() as u32) as _;
+		ptr::write_unaligned(libc::CMSG_DATA(header) as *mut RawFd, fd);
+
+		if libc::sendmsg(socket.as_raw_fd(), &message, libc::MSG_NOSIGNAL) < 0 {
+			return Err(io::Error::last_os_error());
+		}
+	}
+
+	Ok(())
 }
 
-/// Implements a systemd logger
+/// Implements a systemd logger. The messages are sent to journald via its native
+/// protocol. Use the Builder to create it.
 pub struct JournalLogger {
-	level: Level
+	pub(crate) filter: Filter,
+	pub(crate) priorities: PriorityMap,
+	pub(crate) on_send_error: Option<SendErrorCallback>,
+	pub(crate) identifier: Option<String>,
+	pub(crate) mirror_stderr: bool,
+	pub(crate) fallback_stderr: bool,
+	pub(crate) socket_path: PathBuf,
+	pub(crate) socket: Option<UnixDatagram>,
+	pub(crate) fields: Vec<(String, String)>
+}
+
+impl JournalLogger {
+	/// Sends the serialized entry. Entries too large for a datagram are passed within a
+	/// sealed memfd.
+	fn send(&self, entry: &[u8]) -> io::Result<()> {
+		let socket = self.socket.as_ref().ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))?;
+
+		match socket.send_to(entry, &self.socket_path) {
+			Err(ref error) if error.raw_os_error() == Some(libc::EMSGSIZE) || error.raw_os_error() == Some(libc::ENOBUFS) => self.send_memfd(socket, entry),
+			result => result.map(|_| ())
+		}
+	}
+
+	fn send_memfd(&self, socket: &UnixDatagram, entry: &[u8]) -> io::Result<()> {
+		let name = CString::new("journal_logger")?;
+		let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_ALLOW_SEALING | libc::MFD_CLOEXEC) };
+		if fd < 0 {
+			return Err(io::Error::last_os_error());
+		}
+		let mut memfd = unsafe { File::from_raw_fd(fd) };
+		memfd.write_all(entry)?;
+
+		// journald only accepts sealed memfds.
+		if unsafe { libc::fcntl(fd, libc::F_ADD_SEALS, libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_WRITE | libc::F_SEAL_SEAL) } < 0 {
+			return Err(io::Error::last_os_error());
+		}
+
+		send_fd(socket, &self.socket_path, fd)
+	}
+
+	/// Writes the record as a single line to stderr.
+	fn write_stderr(&self, record: &Record) {
+		let _ = io::stderr().write_all(&plain_line(record));
+	}
+
+	/// Serializes the record with its fields and the static fields.
+	fn entry(&self, record: &Record) -> Vec<u8> {
+		let mut fields = Vec::with_capacity(7);
+
+		fields.push((String::from(PRIORITY_KEY), format!("{}", self.priorities.record(record) as u8)));
+
+		fields.push((String::from("MESSAGE"), record.args().to_string()));
+		if let Some(file) = record.file() {
+			if !file.is_empty() {
+				fields.push((String::from("CODE_FILE"), String::from(file)));
+				if let Some(line) = record.line() { fields.push((String::from("CODE_LINE"), line.to_string())) };
+			}
+		}
+
+		if let Some(module_path) = record.module_path() { if !module_path.is_empty() { fields.push((String::from("MODULE_PATH"), String::from(module_path))) } };
+		match self.identifier {
+			Some(ref identifier) => fields.push((String::from("SYSLOG_IDENTIFIER"), identifier.clone())),
+			None => if !record.target().is_empty() { fields.push((String::from("SYSLOG_IDENTIFIER"), String::from(record.target()))) }
+		}
+
+		// The fields of the record are passed on as they are. Collecting them can not fail.
+		let _ = record.key_values().visit(&mut FieldCollector { fields: &mut fields });
+
+		let mut entry = Vec::new();
+		for (key, value) in fields.iter().chain(self.fields.iter()) {
+			append_field(&mut entry, key, value.as_bytes());
+		}
+
+		entry
+	}
+}
+
+/// Prepared entries are sent as a single datagram. journald adds the time of reception.
+impl Prepare for JournalLogger {
+	fn prepare(&self, record: &Record) -> Option<PreparedRecord> {
+		if !log::Log::enabled(self, record.metadata()) { return None; }
+
+		let prepared = PreparedRecord::socket(self.entry(record), None, &self.socket_path).ok()?;
+		Some(if self.mirror_stderr || self.fallback_stderr { prepared.with_stderr(record, self.mirror_stderr) } else { prepared })
+	}
 }
 
 impl log::Log for JournalLogger {
 	fn enabled(&self, metadata: &Metadata) -> bool {
-		metadata.level() <= self.level
+		self.filter.enabled(metadata.target(), metadata.level())
 	}
 
 	fn log(&self, record: &Record) {
 		if self.enabled(record.metadata()) {
-			let mut sd_record = Vec::with_capacity(7);
-
-			sd_record.push(format!("PRIORITY={}",
-				match record.metadata().level() {
-					Level::Debug => Priority::Debug,
-					Level::Trace => Priority::Debug,
-					Level::Info => Priority::Info,
-					Level::Warn => Priority::Warning,
-					Level::Error => Priority::Error
-				} as u8
-			));
-
-			sd_record.push(format!("MESSAGE={}", record.args()));
-			if let Some(file) = record.file() {
-				if !file.is_empty() {
-					sd_record.push(format!("CODE_FILE={}", file));
-					if let Some(line) = record.line() { sd_record.push(format!("CODE_LINE={}", line)) };
-				}
-			}
-
-			if let Some(module_path) = record.module_path() { if !module_path.is_empty() { sd_record.push(format!("MODULE_PATH={}", module_path)) } };
-			if !record.target().is_empty() { sd_record.push(format!("SYSLOG_IDENTIFIER={}", record.target())) };
+			let entry = self.entry(record);
 
-			let iovecs: Vec<iovec> = sd_record.iter().map(|v| {
-				iovec {
-					iov_base: v.as_ptr() as *const libc::c_void,
-					iov_len: v.len() as libc::size_t
-				}
-			}).collect();
-
-			unsafe {
-				sd_journal_sendv(iovecs.as_ptr(), iovecs.len() as libc::c_int);
+			if self.mirror_stderr {
+				self.write_stderr(record);
+			}
+			if let Err(error) = self.send(&entry) {
+				FAILED_SENDS.fetch_add(1, Ordering::Relaxed);
+				if let Some(ref on_send_error) = self.on_send_error { on_send_error(&error); }
+				if self.fallback_stderr && !self.mirror_stderr { self.write_stderr(record); }
 			}
 		}
 	}
@@ -79,13 +238,17 @@ impl log::Log for JournalLogger {
 }
 
 pub fn init_with_level(level: Level) -> Result<(), SetLoggerError> {
-	log::set_boxed_logger(Box::new(JournalLogger {
-		level
-	}))?;
+	Builder::new().level(level.to_level_filter()).init()
+}
 
-	log::set_max_level(level.to_level_filter());
+/// Initializes the logger. The fields are sent with every entry.
+pub fn init_with_fields(level: Level, fields: &[(&str, &str)]) -> Result<(), SetLoggerError> {
+	fields.iter().fold(Builder::new().level(level.to_level_filter()), |builder, (key, value)| builder.field(key, value)).init()
+}
 
-	Ok(())
+/// Initializes the logger with a different socket than the one of journald.
+pub fn init_with_socket(level: Level, socket_path: &Path) -> Result<(), SetLoggerError> {
+	Builder::new().level(level.to_level_filter()).socket(socket_path).init()
 }
 
 #[cfg(test)]
@@ -93,12 +256,26 @@ mod tests
 {
 	use super::*;
 
+	/// Verify the serialization of the fields. Values with a newline are length prefixed.
+	#[test]
+	fn native_fields() {
+		let mut buffer = Vec::new();
+		append_field(&mut buffer, "MESSAGE", b"simple");
+		append_field(&mut buffer, "MULTI", b"a\nb");
+
+		assert_eq!(buffer, b"MESSAGE=simple\nMULTI\n\x03\0\0\0\0\0\0\0a\nb\n");
+	}
+
+	/// Verify that keys are converted into valid field names.
 	#[test]
-	fn layout_iovec() {
-		// This test was adopted from the bindgen autogenerated tests.
-		assert_eq!(std::mem::size_of::<iovec>(), 16usize, concat!("Size of: ", stringify!(iovec)));
-		assert_eq!(std::mem::align_of::<iovec>(), 8usize, concat!("Alignment of ", stringify!(iovec)));
-		assert_eq!(unsafe { & ( * (std::ptr::null::<iovec>())).iov_base as * const _ as usize }, 0usize, concat!("Offset of field: ", stringify!(iovec) , "::" , stringify!(iov_base)));
-		assert_eq!(unsafe { & ( * (std::ptr::null::<iovec>())).iov_len as * const _ as usize }, 8usize, concat!("Offset of field: ", stringify!(iovec), "::" , stringify!(iov_len)));
+	fn field_names() {
+		assert_eq!(field_name("tsos_provider").unwrap(), "TSOS_PROVIDER");
+		assert_eq!(field_name("http.status-code").unwrap(), "HTTP_STATUS_CODE");
+		assert_eq!(field_name("__pid").unwrap(), "PID");
+		assert_eq!(field_name("2fa").unwrap(), "F2FA");
+		assert_eq!(field_name("ümlaut").unwrap(), "MLAUT");
+		assert_eq!(field_name(&"a".repeat(100)).unwrap().len(), MAX_FIELD_NAME);
+		assert!(field_name("___").is_none());
+		assert!(field_name("").is_none());
 	}
-}
\ No newline at end of file
+}
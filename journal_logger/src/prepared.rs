@@ -0,0 +1,154 @@
+use log::Record;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::SystemTime;
+
+use crate::format::write_rfc3339_time;
+use crate::syslog::write_bsd_time;
+
+/// Formats of the timestamps that are filled in when a prepared record is sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TimeFormat {
+	Rfc3339,
+	Rfc3164
+}
+
+/// Where a prepared record is sent to.
+enum Destination {
+	/// A datagram socket like the one of journald or the syslog daemon.
+	Socket(UnixDatagram, libc::sockaddr_un),
+	Stderr
+}
+
+/// Creates the address of a unix socket.
+pub(crate) fn socket_address(socket_path: &Path) -> io::Result<libc::sockaddr_un> {
+	let path = socket_path.as_os_str().as_bytes();
+	let mut address: libc::sockaddr_un = unsafe { mem::zeroed() };
+	if path.len() >= address.sun_path.len() {
+		return Err(io::Error::from(io::ErrorKind::InvalidInput));
+	}
+	address.sun_family = libc::AF_UNIX as libc::sa_family_t;
+	for (target, source) in address.sun_path.iter_mut().zip(path.iter()) {
+		*target = *source as libc::c_char;
+	}
+
+	Ok(address)
+}
+
+/// Writes the data to stderr without allocating memory or taking the lock of stderr.
+fn write_stderr(data: &[u8]) -> io::Result<()> {
+	if unsafe { libc::write(libc::STDERR_FILENO, data.as_ptr() as *const libc::c_void, data.len()) } < 0 {
+		Err(io::Error::last_os_error())
+	} else {
+		Ok(())
+	}
+}
+
+/// A record that was formatted in advance for a logger of this crate. Sending it
+/// neither allocates memory nor takes locks. Therefore it can be sent from a process
+/// that was forked from a multi-threaded one. The time is filled in when it is sent.
+pub struct PreparedRecord {
+	message: Vec<u8>,
+	time: Option<(usize, TimeFormat)>,
+	destination: Destination,
+	/// Written to stderr if sending failed or always if mirror is set.
+	stderr_line: Option<Vec<u8>>,
+	mirror: bool
+}
+
+impl PreparedRecord {
+	/// Sends the message to the socket. The timestamp at the offset is filled in when
+	/// the record is sent.
+	pub(crate) fn socket(message: Vec<u8>, time: Option<(usize, TimeFormat)>, socket_path: &Path) -> io::Result<Self> {
+		Ok(Self {
+			message,
+			time,
+			destination: Destination::Socket(UnixDatagram::unbound()?, socket_address(socket_path)?),
+			stderr_line: None,
+			mirror: false
+		})
+	}
+
+	/// Writes the message to stderr. The timestamp at the offset is filled in when the
+	/// record is sent.
+	pub(crate) fn line(message: Vec<u8>, time: Option<(usize, TimeFormat)>) -> Self {
+		Self {
+			message,
+			time,
+			destination: Destination::Stderr,
+			stderr_line: None,
+			mirror: false
+		}
+	}
+
+	/// Writes the record as a plain line to stderr. Used if the installed logger can not
+	/// prepare records.
+	pub fn stderr(record: &Record) -> Self {
+		Self::line(plain_line(record), None)
+	}
+
+	/// Writes the record as a plain line to stderr if sending it failed. If mirror is set,
+	/// it is written every time.
+	pub(crate) fn with_stderr(mut self, record: &Record, mirror: bool) -> Self {
+		self.stderr_line = Some(plain_line(record));
+		self.mirror = mirror;
+		self
+	}
+
+	/// Sends the record. Can be called more than once.
+	pub fn send(&mut self) -> io::Result<()> {
+		if let Some((offset, format)) = self.time {
+			let time = SystemTime::now();
+			match format {
+				TimeFormat::Rfc3339 => write_rfc3339_time(time, &mut self.message[offset..]),
+				TimeFormat::Rfc3164 => write_bsd_time(time, &mut self.message[offset..])
+			}
+		}
+
+		let result = match self.destination {
+			Destination::Socket(ref socket, ref address) => {
+				let sent = unsafe { libc::sendto(socket.as_raw_fd(), self.message.as_ptr() as *const libc::c_void, self.message.len(), libc::MSG_NOSIGNAL,
+					address as *const libc::sockaddr_un as *const libc::sockaddr, mem::size_of::<libc::sockaddr_un>() as libc::socklen_t) };
+				if sent < 0 { Err(io::Error::last_os_error()) } else { Ok(()) }
+			},
+			Destination::Stderr => write_stderr(&self.message)
+		};
+
+		if let Some(ref stderr_line) = self.stderr_line {
+			if self.mirror || result.is_err() { let _ = write_stderr(stderr_line); }
+		}
+
+		result
+	}
+}
+
+/// Formats the record as a single line like the stderr fallback of the loggers.
+pub(crate) fn plain_line(record: &Record) -> Vec<u8> {
+	format!("{} [{}] {}\n", record.level(), record.target(), record.args()).into_bytes()
+}
+
+/// Implemented by the loggers of this crate.
+pub trait Prepare {
+	/// Formats the record in advance. Returns None if the record is filtered or can not
+	/// be prepared.
+	fn prepare(&self, record: &Record) -> Option<PreparedRecord>;
+}
+
+/// The logger installed by init.
+static INSTALLED: OnceLock<&'static (dyn Prepare + Send + Sync)> = OnceLock::new();
+
+pub(crate) fn set_installed(logger: &'static (dyn Prepare + Send + Sync)) {
+	let _ = INSTALLED.set(logger);
+}
+
+/// Formats the record in advance for the logger installed by init. Returns None if the
+/// record is filtered, the logger can not prepare it or the global logger is not one
+/// of this crate.
+pub fn prepare(record: &Record) -> Option<PreparedRecord> {
+	INSTALLED.get().and_then(|logger| logger.prepare(record))
+}
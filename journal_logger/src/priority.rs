@@ -0,0 +1,147 @@
+use log::{Level, Record};
+use log::kv::{Key, Value, VisitSource};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::logger::{field_name, PRIORITY_KEY};
+
+/// Priorities as defined within syslog.h.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+	/// System is unusable
+	Emerg = 0,
+	/// Action must be taken immediately
+	Alert = 1,
+	/// Critical conditions
+	Crit = 2,
+	/// Error conditions
+	Err = 3,
+	/// Warning conditions
+	Warning = 4,
+	/// Normal but significant condition
+	Notice = 5,
+	/// Informational
+	Info = 6,
+	/// Debug-level messages
+	Debug = 7
+}
+
+/// Error returned if a string is neither a priority name nor a number between 0 and 7.
+#[derive(Debug, PartialEq)]
+pub struct ParsePriorityError;
+
+impl fmt::Display for ParsePriorityError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "Invalid syslog priority.")
+	}
+}
+
+impl std::error::Error for ParsePriorityError {}
+
+impl FromStr for Priority {
+	type Err = ParsePriorityError;
+
+	/// Accepts the names used by syslog and journalctl and the numeric values.
+	fn from_str(value: &str) -> Result<Self, ParsePriorityError> {
+		match value.trim().to_ascii_lowercase().as_str() {
+			"emerg" | "0" => Ok(Self::Emerg),
+			"alert" | "1" => Ok(Self::Alert),
+			"crit" | "2" => Ok(Self::Crit),
+			"err" | "error" | "3" => Ok(Self::Err),
+			"warning" | "warn" | "4" => Ok(Self::Warning),
+			"notice" | "5" => Ok(Self::Notice),
+			"info" | "6" => Ok(Self::Info),
+			"debug" | "7" => Ok(Self::Debug),
+			_ => Err(ParsePriorityError)
+		}
+	}
+}
+
+/// Maps the levels of the log crate onto syslog priorities.
+#[derive(Debug, Clone)]
+pub struct PriorityMap {
+	priorities: [Priority; 5]
+}
+
+impl Default for PriorityMap {
+	fn default() -> Self {
+		Self {
+			priorities: [ Priority::Err, Priority::Warning, Priority::Info, Priority::Debug, Priority::Debug ]
+		}
+	}
+}
+
+impl PriorityMap {
+	/// Index of the level within priorities. Level::Error is 1.
+	fn index(level: Level) -> usize {
+		level as usize - 1
+	}
+
+	pub fn set(&mut self, level: Level, priority: Priority) {
+		self.priorities[Self::index(level)] = priority;
+	}
+
+	pub fn get(&self, level: Level) -> Priority {
+		self.priorities[Self::index(level)]
+	}
+
+	/// The priority of the record. A `priority` key-value takes precedence over the
+	/// mapping of the level. Invalid priority key-values are ignored.
+	pub fn record(&self, record: &Record) -> Priority {
+		let mut visitor = OverrideVisitor { priority: None };
+		let _ = record.key_values().visit(&mut visitor);
+
+		visitor.priority.unwrap_or_else(|| self.get(record.level()))
+	}
+}
+
+/// Returns the priority if the key-value overrides the priority of a record. The key
+/// is compared after converting it into a field name (e.g. `Priority` or `PRIORITY`).
+/// Key-values with an invalid priority are kept as INVALID_PRIORITY field.
+pub(crate) fn priority_override(key: &Key, value: &Value) -> Option<Priority> {
+	if field_name(key.as_str()).as_deref() == Some(PRIORITY_KEY) {
+		Priority::from_str(&value.to_string()).ok()
+	} else {
+		None
+	}
+}
+
+/// Finds the first key-value overriding the priority.
+struct OverrideVisitor {
+	priority: Option<Priority>
+}
+
+impl<'kvs> VisitSource<'kvs> for OverrideVisitor {
+	fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+		if self.priority.is_none() { self.priority = priority_override(&key, &value); }
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	/// Verify that names and numbers are parsed.
+	#[test]
+	fn parse_priority() {
+		assert_eq!(Priority::from_str("crit"), Ok(Priority::Crit));
+		assert_eq!(Priority::from_str("WARN"), Ok(Priority::Warning));
+		assert_eq!(Priority::from_str("5"), Ok(Priority::Notice));
+		assert_eq!(Priority::from_str("8"), Err(ParsePriorityError));
+	}
+
+	/// Verify the default mapping and overriding it.
+	#[test]
+	fn priority_map() {
+		let mut map = PriorityMap::default();
+		assert_eq!(map.get(Level::Error), Priority::Err);
+		assert_eq!(map.get(Level::Trace), Priority::Debug);
+
+		map.set(Level::Info, Priority::Notice);
+		assert_eq!(map.get(Level::Info), Priority::Notice);
+		assert_eq!(map.get(Level::Warn), Priority::Warning);
+	}
+}
@@ -0,0 +1,351 @@
+use log::{Record, Metadata};
+use std::io::{self, Write};
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::Ordering;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::Config;
+use crate::filter::Filter;
+use crate::format::{civil_date, rfc3339_time, write_digits};
+use crate::logger::{FieldCollector, SendErrorCallback, FAILED_SENDS};
+use crate::priority::{Priority, PriorityMap};
+use crate::prepared::{plain_line, Prepare, PreparedRecord, TimeFormat};
+
+/// Socket the local syslog daemon receives messages on.
+pub const SYSLOG_SOCKET: &str = "/dev/log";
+
+/// Structured data id of the fields within RFC 5424 messages. 32473 is the private
+/// enterprise number reserved for documentation (RFC 5612).
+pub const STRUCTURED_DATA_ID: &str = "fields@32473";
+
+/// Maximum length of a parameter name within RFC 5424 structured data.
+const MAX_PARAM_NAME: usize = 32;
+
+/// Maximum length of the APP-NAME of RFC 5424. RFC 3164 limits the tag to 32 characters.
+const MAX_APP_NAME: usize = 48;
+const MAX_TAG: usize = 32;
+
+const MONTHS: [&str; 12] = [ "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec" ];
+
+/// Message formats understood by syslog daemons.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyslogFormat {
+	/// The current syslog protocol including structured data.
+	Rfc5424,
+	/// The legacy BSD syslog format. Fields are appended to the message.
+	Rfc3164
+}
+
+/// Facilities as defined within syslog.h.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Facility {
+	Kern = 0,
+	User = 1,
+	Mail = 2,
+	Daemon = 3,
+	Auth = 4,
+	Syslog = 5,
+	Lpr = 6,
+	News = 7,
+	Uucp = 8,
+	Cron = 9,
+	AuthPriv = 10,
+	Ftp = 11,
+	Local0 = 16,
+	Local1 = 17,
+	Local2 = 18,
+	Local3 = 19,
+	Local4 = 20,
+	Local5 = 21,
+	Local6 = 22,
+	Local7 = 23
+}
+
+/// Length of the timestamps written by write_bsd_time.
+const BSD_TIME_LENGTH: usize = 15;
+
+/// Writes the time in UTC as RFC 3164 timestamp of the form `Oct  8 12:00:00` into the
+/// first BSD_TIME_LENGTH bytes of the buffer. Does not allocate memory.
+pub(crate) fn write_bsd_time(time: SystemTime, out: &mut [u8]) {
+	let seconds = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+	let (_, month, day) = civil_date((seconds / 86400) as i64);
+	let seconds = seconds % 86400;
+
+	out[..BSD_TIME_LENGTH].copy_from_slice(b"Jan 00 00:00:00");
+	out[..3].copy_from_slice(MONTHS[(month - 1) as usize].as_bytes());
+	write_digits(&mut out[4..6], day as u64);
+	if day < 10 { out[4] = b' '; }
+	write_digits(&mut out[7..9], seconds / 3600);
+	write_digits(&mut out[10..12], seconds / 60 % 60);
+	write_digits(&mut out[13..15], seconds % 60);
+}
+
+/// Formats the time in UTC. RFC 5424 uses RFC 3339 timestamps, RFC 3164 timestamps
+/// of the form `Oct  8 12:00:00`.
+pub(crate) fn format_time(time: SystemTime, format: SyslogFormat) -> String {
+	if format == SyslogFormat::Rfc5424 {
+		return rfc3339_time(time);
+	}
+
+	let mut buffer = [0u8; BSD_TIME_LENGTH];
+	write_bsd_time(time, &mut buffer);
+
+	String::from_utf8_lossy(&buffer).into_owned()
+}
+
+/// Replaces characters that are not printable ASCII and truncates the name. Returns
+/// the nil value `-` if nothing is left.
+fn header_name(name: &str, max_length: usize) -> String {
+	let mut name: String = name.chars().map(|c| if c.is_ascii_graphic() { c } else { '_' }).collect();
+	name.truncate(max_length);
+
+	if name.is_empty() { String::from("-") } else { name }
+}
+
+/// Returns the host name or the nil value `-` if it is not available.
+fn hostname() -> String {
+	let mut buffer = [0u8; 256];
+	if unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) } != 0 {
+		return String::from("-");
+	}
+	let length = buffer.iter().position(|v| *v == 0).unwrap_or(buffer.len());
+
+	header_name(&String::from_utf8_lossy(&buffer[..length]), 255)
+}
+
+/// Formats the fields as a structured data element of RFC 5424. Returns the nil value
+/// `-` if there are no fields.
+fn structured_data(id: &str, fields: &[(String, String)]) -> String {
+	if fields.is_empty() {
+		return String::from("-");
+	}
+
+	let mut data = format!("[{}", id);
+	for (name, value) in fields.iter() {
+		data.push(' ');
+		data.push_str(&name[..name.len().min(MAX_PARAM_NAME)]);
+		data.push_str("=\"");
+		for c in value.chars() {
+			if c == '"' || c == '\\' || c == ']' { data.push('\\'); }
+			data.push(c);
+		}
+		data.push('"');
+	}
+	data.push(']');
+
+	data
+}
+
+/// Implements a logger sending the messages to the local syslog daemon. Use the
+/// SyslogBuilder to create it.
+pub struct SyslogLogger {
+	filter: Filter,
+	priorities: PriorityMap,
+	on_send_error: Option<SendErrorCallback>,
+	identifier: Option<String>,
+	facility: Facility,
+	format: SyslogFormat,
+	fallback_stderr: bool,
+	hostname: String,
+	socket_path: PathBuf,
+	socket: Option<UnixDatagram>,
+	fields: Vec<(String, String)>
+}
+
+impl SyslogLogger {
+	/// Formats the message. The fields of the record are followed by the static fields.
+	fn format(&self, time: SystemTime, priority: Priority, target: &str, message: &str, fields: &[(String, String)]) -> String {
+		let pri = (self.facility as u8) * 8 + priority as u8;
+		let identifier = self.identifier.as_deref().unwrap_or(target);
+
+		match self.format {
+			SyslogFormat::Rfc5424 => format!("<{}>1 {} {} {} {} - {} {}", pri, format_time(time, self.format), self.hostname, header_name(identifier, MAX_APP_NAME), process::id(), structured_data(STRUCTURED_DATA_ID, fields), message),
+			SyslogFormat::Rfc3164 => {
+				let mut line = format!("<{}>{} {}[{}]: {}", pri, format_time(time, self.format), header_name(identifier, MAX_TAG), process::id(), message);
+				for (name, value) in fields.iter() {
+					line.push_str(&format!(" {}={}", name, value));
+				}
+				line
+			}
+		}
+	}
+
+	/// Formats the record with its fields followed by the static fields.
+	fn message(&self, time: SystemTime, record: &Record) -> String {
+		// The fields use the same names as within the journal.
+		let mut fields = Vec::new();
+		let _ = record.key_values().visit(&mut FieldCollector { fields: &mut fields });
+		fields.extend(self.fields.iter().cloned());
+
+		self.format(time, self.priorities.record(record), record.target(), &record.args().to_string(), &fields)
+	}
+
+	fn send(&self, message: &[u8]) -> io::Result<()> {
+		let socket = self.socket.as_ref().ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))?;
+		socket.send_to(message, &self.socket_path).map(|_| ())
+	}
+}
+
+/// The timestamp follows the PRI (and the version of RFC 5424).
+impl Prepare for SyslogLogger {
+	fn prepare(&self, record: &Record) -> Option<PreparedRecord> {
+		if !log::Log::enabled(self, record.metadata()) { return None; }
+
+		let message = self.message(UNIX_EPOCH, record);
+		let offset = message.find('>')? + 1;
+		let time = match self.format {
+			SyslogFormat::Rfc5424 => (offset + 2, TimeFormat::Rfc3339),
+			SyslogFormat::Rfc3164 => (offset, TimeFormat::Rfc3164)
+		};
+
+		let prepared = PreparedRecord::socket(message.into_bytes(), Some(time), &self.socket_path).ok()?;
+		Some(if self.fallback_stderr { prepared.with_stderr(record, false) } else { prepared })
+	}
+}
+
+impl log::Log for SyslogLogger {
+	fn enabled(&self, metadata: &Metadata) -> bool {
+		self.filter.enabled(metadata.target(), metadata.level())
+	}
+
+	fn log(&self, record: &Record) {
+		if self.enabled(record.metadata()) {
+			let message = self.message(SystemTime::now(), record);
+			if let Err(error) = self.send(message.as_bytes()) {
+				FAILED_SENDS.fetch_add(1, Ordering::Relaxed);
+				if let Some(ref on_send_error) = self.on_send_error { on_send_error(&error); }
+				if self.fallback_stderr { let _ = io::stderr().write_all(&plain_line(record)); }
+			}
+		}
+	}
+
+	fn flush(&self) {
+		// Datagrams are not buffered.
+	}
+}
+
+/// Configures and creates a SyslogLogger.
+pub struct SyslogBuilder {
+	config: Config,
+	priorities: PriorityMap,
+	on_send_error: Option<SendErrorCallback>,
+	identifier: Option<String>,
+	facility: Facility,
+	format: SyslogFormat,
+	fallback_stderr: bool,
+	socket_path: PathBuf
+}
+
+impl Default for SyslogBuilder {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl SyslogBuilder {
+	/// Creates a builder logging everything up to the level info as RFC 5424 messages
+	/// with the facility daemon to /dev/log.
+	pub fn new() -> Self {
+		Self {
+			config: Config::new(),
+			priorities: PriorityMap::default(),
+			on_send_error: None,
+			identifier: None,
+			facility: Facility::Daemon,
+			format: SyslogFormat::Rfc5424,
+			fallback_stderr: false,
+			socket_path: PathBuf::from(SYSLOG_SOCKET)
+		}
+	}
+
+	config_methods!();
+	send_methods!();
+
+	/// Sets the APP-NAME (RFC 5424) or tag (RFC 3164) of all messages. By default the
+	/// target of the record is used.
+	pub fn identifier(mut self, identifier: &str) -> Self {
+		self.identifier = Some(String::from(identifier));
+		self
+	}
+
+	pub fn facility(mut self, facility: Facility) -> Self {
+		self.facility = facility;
+		self
+	}
+
+	pub fn format(mut self, format: SyslogFormat) -> Self {
+		self.format = format;
+		self
+	}
+
+	/// Writes messages to stderr that could not be sent to the syslog daemon.
+	pub fn fallback_stderr(mut self, fallback: bool) -> Self {
+		self.fallback_stderr = fallback;
+		self
+	}
+
+	/// Sends the messages to a different socket than /dev/log.
+	pub fn socket(mut self, socket_path: &Path) -> Self {
+		self.socket_path = PathBuf::from(socket_path);
+		self
+	}
+
+	pub fn build(self) -> SyslogLogger {
+		SyslogLogger {
+			filter: self.config.filter,
+			priorities: self.priorities,
+			on_send_error: self.on_send_error,
+			identifier: self.identifier,
+			facility: self.facility,
+			format: self.format,
+			fallback_stderr: self.fallback_stderr,
+			hostname: hostname(),
+			socket_path: self.socket_path,
+			socket: UnixDatagram::unbound().ok(),
+			fields: self.config.fields
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use std::time::Duration;
+
+	/// Verify the timestamps of both formats.
+	#[test]
+	fn timestamps() {
+		let time = UNIX_EPOCH + Duration::new(1792366262, 123456000);
+		assert_eq!(format_time(time, SyslogFormat::Rfc5424), "2026-10-18T23:31:02.123456Z");
+		assert_eq!(format_time(UNIX_EPOCH + Duration::new(951782400, 0), SyslogFormat::Rfc3164), "Feb 29 00:00:00");
+		assert_eq!(format_time(UNIX_EPOCH + Duration::new(1791460800, 0), SyslogFormat::Rfc3164), "Oct  8 12:00:00");
+	}
+
+	/// Verify the escaping of the structured data.
+	#[test]
+	fn structured_data_escaping() {
+		assert_eq!(structured_data(STRUCTURED_DATA_ID, &[]), "-");
+		assert_eq!(structured_data(STRUCTURED_DATA_ID, &[ (String::from("A"), String::from("x\"y\\z]")), (String::from("B"), String::new()) ]), "[fields@32473 A=\"x\\\"y\\\\z\\]\" B=\"\"]");
+		assert_eq!(header_name("my app", MAX_APP_NAME), "my_app");
+		assert_eq!(header_name("", MAX_APP_NAME), "-");
+	}
+
+	/// Verify the layout of both formats.
+	#[test]
+	fn messages() {
+		let mut logger = SyslogBuilder::new().identifier("tsos").facility(Facility::Local0).build();
+		logger.hostname = String::from("host");
+		let time = UNIX_EPOCH + Duration::new(1792366262, 0);
+		let fields = [ (String::from("TSOS_EXEC"), String::from("/usr/bin/app")) ];
+
+		assert_eq!(logger.format(time, Priority::Err, "target", "Failed", &fields), format!("<131>1 2026-10-18T23:31:02.000000Z host tsos {} - [fields@32473 TSOS_EXEC=\"/usr/bin/app\"] Failed", process::id()));
+
+		logger.format = SyslogFormat::Rfc3164;
+		logger.identifier = None;
+		assert_eq!(logger.format(time, Priority::Info, "target", "Started", &fields), format!("<134>Oct 18 23:31:02 target[{}]: Started TSOS_EXEC=/usr/bin/app", process::id()));
+	}
+}
@@ -1,11 +1,11 @@
-use std::io::BufRead;
-use std::process::Command;
-use std::io::Cursor;
+use std::io::{Read, Seek, SeekFrom};
+use std::fs::File;
 use log::{Level, info};
 use std::{fs, env};
 use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::UnixDatagram;
 use std::collections::HashMap;
-use std::time::SystemTime;
 
 extern crate journal_logger;
 
@@ -31,50 +31,293 @@ fn has_journal() {
 	assert!(!journal_logger::has_journal());
 }
 
-/// Test the journal logger by wrting a journal entry and using journalctl
-/// to read the entry back. The fields of the entry are checked if they contain
-/// the correct values.
-#[test]
-fn write_log() {
-	let start_time = SystemTime::now();
+/// Receives a datagram from the stand-in socket. If it contains a file descriptor
+/// instead of a payload, the content of the file is returned.
+fn receive(socket: &UnixDatagram) -> Vec<u8> {
+	let mut buffer = vec![0u8; 65536];
+	let mut control = [0u64; 8];
+	let mut iov = libc::iovec { iov_base: buffer.as_mut_ptr() as *mut libc::c_void, iov_len: buffer.len() };
+	let mut message: libc::msghdr = unsafe { std::mem::zeroed() };
+	message.msg_iov = &mut iov;
+	message.msg_iovlen = 1;
+	message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+	message.msg_controllen = std::mem::size_of_val(&control) as _;
 
-	assert!(journal_logger::init_with_level(Level::Debug).is_ok());
+	let length = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut message, 0) };
+	assert!(length >= 0, "Receiving from the socket failed");
 
-	// Log a line and save the line this has been done for later reference within the assert.
-	info!(target: "journal_logger_test", "Testing the journal_logger crate."); let log_line = line!();
-
-	// Read the journal from the start timestamp of this test onward and extract the text journal entry
-	let output = Command::new("journalctl")
-	.arg("-le")
-	.arg(format!("-S@{}", start_time.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs()))
-	.arg("-tjournal_logger_test")
-	.arg("-oexport")
-	.output().unwrap();
-
-	// Parse the journal entry and create a HashMap for checking the values.
-	let mut stdout = Cursor::new(output.stdout);
-	let mut buffer = String::new();
-	let mut values = HashMap::new();
-	while let Ok(len) = stdout.read_line(&mut buffer) {
-		// Exit on eof
-		if len == 0 { break }
-		let key_value = buffer.trim().splitn(2, '=');
+	let header = unsafe { libc::CMSG_FIRSTHDR(&message) };
+	if header.is_null() {
+		buffer.truncate(length as usize);
+		buffer
+	} else {
+		assert_eq!(length, 0, "Payload next to a file descriptor");
+		let mut file = unsafe { File::from_raw_fd(std::ptr::read_unaligned(libc::CMSG_DATA(header) as *const i32)) };
+		let mut content = Vec::new();
+		file.seek(SeekFrom::Start(0)).unwrap();
+		file.read_to_end(&mut content).unwrap();
+		content
+	}
+}
 
-		if let [key, value] = key_value.collect::<Vec<&str>>()[0..] {
-			values.insert(String::from(key), String::from(value));
-		}
+/// Parses an entry of the native journal protocol.
+fn parse(mut entry: &[u8]) -> HashMap<String, String> {
+	let mut values = HashMap::new();
 
-		// Clear the buffer for the next round
-		buffer.clear();
+	while !entry.is_empty() {
+		let end = entry.iter().position(|v| *v == b'=' || *v == b'\n').unwrap();
+		let key = String::from_utf8_lossy(&entry[..end]).into_owned();
+		let value = if entry[end] == b'=' {
+			let length = entry[end + 1..].iter().position(|v| *v == b'\n').unwrap();
+			let value = &entry[end + 1..end + 1 + length];
+			entry = &entry[end + 2 + length..];
+			value
+		} else {
+			let mut length = [0u8; 8];
+			length.copy_from_slice(&entry[end + 1..end + 9]);
+			let length = u64::from_le_bytes(length) as usize;
+			let value = &entry[end + 9..end + 9 + length];
+			assert_eq!(entry[end + 9 + length], b'\n', "Value not terminated");
+			entry = &entry[end + 10 + length..];
+			value
+		};
+		values.insert(key, String::from_utf8_lossy(value).into_owned());
 	}
 
+	values
+}
+
+/// Test the journal logger by writing journal entries to a socket standing in for
+/// journald. The fields of the entries are checked if they contain the correct values.
+#[test]
+fn write_log() {
+	let socket_path = env::temp_dir().join(format!("journal_logger_test-{}.socket", std::process::id()));
+	let _ = fs::remove_file(&socket_path);
+	let socket = UnixDatagram::bind(&socket_path).unwrap();
+
+	assert!(journal_logger::init_with_socket(Level::Debug, &socket_path).is_ok());
+
+	// Log a line and save the line this has been done for later reference within the assert.
+	info!(target: "journal_logger_test", test_field = "field value"; "Testing the journal_logger crate."); let log_line = line!();
+	let values = parse(&receive(&socket));
+
 	// Verify the content of the journal entry
 	assert_eq!(values.get("MESSAGE").unwrap(), "Testing the journal_logger crate.", "Wrong MESSAGE content.");
 	assert_eq!(values.get("PRIORITY").unwrap(), "6", "Wrong PRIORITY field.");
-	assert_eq!(values.get("_TRANSPORT").unwrap(), "journal", "Message transport was not journal.");
 	assert_eq!(values.get("MODULE_PATH").unwrap(), "lib", "Module path not 'lib'.");
 	assert_eq!(values.get("CODE_LINE").unwrap(), &format!("{}", log_line), "Wrong CODE_LINE field.");
 	assert_eq!(values.get("CODE_FILE").unwrap(), file!(), "Wrong CODE_FILE field.");
-	assert_eq!(values.get("_PID").unwrap(), &format!("{}", std::process::id()), "Journal _PID field does not contain test process PID.");
 	assert_eq!(values.get("SYSLOG_IDENTIFIER").unwrap(), "journal_logger_test", "Wrong SYSLOG_IDENTIFIER field.");
-}
\ No newline at end of file
+	assert_eq!(values.get("TEST_FIELD").unwrap(), "field value", "Key-value not passed as journal field.");
+
+	// Messages with newlines are length prefixed.
+	info!(target: "journal_logger_test", "first\nsecond");
+	assert_eq!(parse(&receive(&socket)).get("MESSAGE").unwrap(), "first\nsecond", "Wrong multi line MESSAGE content.");
+
+	// Entries too large for a datagram are sent within a memfd.
+	let large = "x".repeat(1024 * 1024);
+	info!(target: "journal_logger_test", "{}", large);
+	assert_eq!(parse(&receive(&socket)).get("MESSAGE").unwrap(), &large, "Wrong large MESSAGE content.");
+
+	fs::remove_file(&socket_path).unwrap();
+}
+
+/// Verify that static fields are sent with every entry and that the keys of key-values
+/// are converted into valid field names.
+#[test]
+fn static_fields() {
+	use log::Log;
+
+	let socket_path = env::temp_dir().join(format!("journal_logger_fields-{}.socket", std::process::id()));
+	let _ = fs::remove_file(&socket_path);
+	let socket = UnixDatagram::bind(&socket_path).unwrap();
+
+	let logger = journal_logger::Builder::new()
+		.socket(&socket_path)
+		.field("tsos_config", "/etc/tsos/app.toml")
+		.field("TSOS_EXEC", "/usr/bin/app")
+		.identifier("tsos")
+		.build();
+
+	let key_values = [ ("provider.name", "vault"), ("_hidden", "no") ];
+	logger.log(&log::Record::builder()
+		.args(format_args!("Static fields"))
+		.level(Level::Info)
+		.target("journal_logger_test")
+		.key_values(&key_values)
+		.build());
+	let values = parse(&receive(&socket));
+
+	assert_eq!(values.get("TSOS_CONFIG").unwrap(), "/etc/tsos/app.toml", "Static field missing.");
+	assert_eq!(values.get("TSOS_EXEC").unwrap(), "/usr/bin/app", "Static field missing.");
+	assert_eq!(values.get("PROVIDER_NAME").unwrap(), "vault", "Key-value not sanitized.");
+	assert_eq!(values.get("HIDDEN").unwrap(), "no", "Leading underscore not removed.");
+	assert_eq!(values.get("SYSLOG_IDENTIFIER").unwrap(), "tsos", "Identifier not used.");
+
+	fs::remove_file(&socket_path).unwrap();
+}
+
+/// Verify the mapping of levels onto syslog priorities and overriding the priority with
+/// a key-value.
+#[test]
+fn priorities() {
+	use log::Log;
+	use journal_logger::Priority;
+
+	let socket_path = env::temp_dir().join(format!("journal_logger_priorities-{}.socket", std::process::id()));
+	let _ = fs::remove_file(&socket_path);
+	let socket = UnixDatagram::bind(&socket_path).unwrap();
+
+	let logger = journal_logger::Builder::new()
+		.socket(&socket_path)
+		.priority(Level::Info, Priority::Notice)
+		.build();
+
+	logger.log(&log::Record::builder().args(format_args!("Mapped")).level(Level::Info).target("journal_logger_test").build());
+	assert_eq!(parse(&receive(&socket)).get("PRIORITY").unwrap(), "5", "Level mapping not used.");
+
+	let key_values = [ ("priority", "crit") ];
+	logger.log(&log::Record::builder().args(format_args!("Override")).level(Level::Error).target("journal_logger_test").key_values(&key_values).build());
+	assert_eq!(parse(&receive(&socket)).get("PRIORITY").unwrap(), "2", "Priority key-value not used.");
+
+	let key_values = [ ("Priority", "alert") ];
+	logger.log(&log::Record::builder().args(format_args!("Override")).level(Level::Error).target("journal_logger_test").key_values(&key_values).build());
+	assert_eq!(parse(&receive(&socket)).get("PRIORITY").unwrap(), "1", "Priority key-value is case sensitive.");
+
+	let key_values = [ ("priority", "loud") ];
+	logger.log(&log::Record::builder().args(format_args!("Invalid")).level(Level::Warn).target("journal_logger_test").key_values(&key_values).build());
+	let values = parse(&receive(&socket));
+	assert_eq!(values.get("PRIORITY").unwrap(), "4", "Invalid priority key-value not ignored.");
+	assert_eq!(values.get("INVALID_PRIORITY").unwrap(), "loud", "Invalid priority key-value dropped.");
+
+	fs::remove_file(&socket_path).unwrap();
+}
+
+/// Verify that failed sends are counted and reported to the callback.
+#[test]
+fn failed_sends() {
+	use log::Log;
+	use std::sync::Arc;
+	use std::sync::atomic::{AtomicUsize, Ordering};
+
+	let reported = Arc::new(AtomicUsize::new(0));
+	let callback_reported = reported.clone();
+	let logger = journal_logger::Builder::new()
+		.socket(&env::temp_dir().join(format!("journal_logger_missing-{}.socket", std::process::id())))
+		.on_send_error(move |_| { callback_reported.fetch_add(1, Ordering::SeqCst); })
+		.build();
+
+	let before = journal_logger::failed_sends();
+	logger.log(&log::Record::builder().args(format_args!("Lost")).level(Level::Error).target("journal_logger_test").build());
+
+	assert_eq!(reported.load(Ordering::SeqCst), 1, "Callback not called.");
+	assert!(journal_logger::failed_sends() > before, "Failed send not counted.");
+}
+
+/// Verify that syslog messages are sent to the stand-in socket with the fields as
+/// structured data.
+#[test]
+fn syslog() {
+	use log::Log;
+	use journal_logger::{Facility, SyslogFormat};
+
+	let socket_path = env::temp_dir().join(format!("journal_logger_syslog-{}.socket", std::process::id()));
+	let _ = fs::remove_file(&socket_path);
+	let socket = UnixDatagram::bind(&socket_path).unwrap();
+
+	let builder = journal_logger::SyslogBuilder::new()
+		.socket(&socket_path)
+		.identifier("tsos")
+		.field("tsos_exec", "/usr/bin/app");
+	let key_values = [ ("provider", "vault") ];
+	let record = log::Record::builder().args(format_args!("Provider failed")).level(Level::Error).target("journal_logger_test").key_values(&key_values).build();
+
+	builder.build().log(&record);
+	let message = String::from_utf8(receive(&socket)).unwrap();
+	assert!(message.starts_with("<27>1 "), "Wrong priority or version: {}", message);
+	assert!(message.contains(&format!(" tsos {} - [fields@32473 PROVIDER=\"vault\" TSOS_EXEC=\"/usr/bin/app\"] Provider failed", std::process::id())), "Wrong message: {}", message);
+
+	let logger = journal_logger::SyslogBuilder::new().socket(&socket_path).format(SyslogFormat::Rfc3164).facility(Facility::Local7).build();
+	logger.log(&record);
+	let message = String::from_utf8(receive(&socket)).unwrap();
+	assert!(message.starts_with("<187>"), "Wrong priority: {}", message);
+	assert!(message.ends_with(&format!(" journal_logger_test[{}]: Provider failed PROVIDER=vault", std::process::id())), "Wrong message: {}", message);
+
+	fs::remove_file(&socket_path).unwrap();
+}
+
+/// Verify that the JSON logger writes one object per record with the fields named
+/// like within the journal.
+#[test]
+fn json_lines() {
+	use log::Log;
+	use std::io::{self, Write};
+	use std::sync::{Arc, Mutex};
+
+	#[derive(Clone)]
+	struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+	impl Write for Buffer {
+		fn write(&mut self, data: &[u8]) -> io::Result<usize> { self.0.lock().unwrap().write(data) }
+		fn flush(&mut self) -> io::Result<()> { Ok(()) }
+	}
+
+	let buffer = Buffer(Arc::new(Mutex::new(Vec::new())));
+	let logger = journal_logger::JsonBuilder::new()
+		.writer(buffer.clone())
+		.field("tsos_exec", "/usr/bin/app")
+		.build();
+
+	let key_values = [ ("provider.name", "vault") ];
+	logger.log(&log::Record::builder().args(format_args!("Provider failed")).level(Level::Error).target("journal_logger_test").key_values(&key_values).build());
+	logger.log(&log::Record::builder().args(format_args!("Hidden")).level(Level::Debug).target("journal_logger_test").build());
+
+	let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+	assert_eq!(output.lines().count(), 1, "Filtered record written.");
+	assert!(output.starts_with("{\"timestamp\":\""), "Timestamp missing: {}", output);
+	assert!(output.ends_with(",\"level\":\"ERROR\",\"target\":\"journal_logger_test\",\"message\":\"Provider failed\",\"PROVIDER_NAME\":\"vault\",\"TSOS_EXEC\":\"/usr/bin/app\"}\n"), "Wrong line: {}", output);
+}
+
+/// Verify that prepared records are sent from a forked process and that the time is
+/// filled in when they are sent.
+#[test]
+fn prepared_records() {
+	use journal_logger::Prepare;
+
+	let socket_path = env::temp_dir().join(format!("journal_logger_prepared-{}.socket", std::process::id()));
+	let _ = fs::remove_file(&socket_path);
+	let socket = UnixDatagram::bind(&socket_path).unwrap();
+
+	let key_values = [ ("provider", "vault") ];
+	let record = log::Record::builder().args(format_args!("Prepared")).level(Level::Warn).target("journal_logger_test").key_values(&key_values).build();
+
+	let logger = journal_logger::Builder::new().socket(&socket_path).build();
+	let mut prepared = logger.prepare(&record).unwrap();
+	let child = unsafe { libc::fork() };
+	if child == 0 {
+		let exit_code = if prepared.send().is_ok() { 0 } else { 1 };
+		unsafe { libc::_exit(exit_code) };
+	}
+	let mut status = 0;
+	assert_eq!(unsafe { libc::waitpid(child, &mut status, 0) }, child);
+	assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0, "Sending within the child failed.");
+
+	let values = parse(&receive(&socket));
+	assert_eq!(values.get("MESSAGE").unwrap(), "Prepared", "Wrong MESSAGE content.");
+	assert_eq!(values.get("PRIORITY").unwrap(), "4", "Wrong PRIORITY field.");
+	assert_eq!(values.get("PROVIDER").unwrap(), "vault", "Key-value not passed as journal field.");
+
+	let logger = journal_logger::SyslogBuilder::new().socket(&socket_path).identifier("tsos").build();
+	logger.prepare(&record).unwrap().send().unwrap();
+	let message = String::from_utf8(receive(&socket)).unwrap();
+	assert!(message.starts_with("<28>1 "), "Wrong priority or version: {}", message);
+	assert!(!message.starts_with("<28>1 1970-"), "Time not filled in: {}", message);
+	assert!(message.ends_with("[fields@32473 PROVIDER=\"vault\"] Prepared"), "Wrong message: {}", message);
+
+	// Filtered records and custom writers can not be prepared.
+	let debug = log::Record::builder().args(format_args!("Hidden")).level(Level::Debug).target("journal_logger_test").build();
+	assert!(logger.prepare(&debug).is_none(), "Filtered record prepared.");
+	assert!(journal_logger::JsonBuilder::new().writer(Vec::new()).build().prepare(&record).is_none(), "Record for a custom writer prepared.");
+
+	fs::remove_file(&socket_path).unwrap();
+}
@@ -0,0 +1,139 @@
+use std::path::Path;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::time::SystemTime;
+use std::fmt::{self, Display};
+
+use log::{Level, Metadata, Record};
+use journal_logger::{rfc3339_time, write_json_string};
+
+use crate::system::{UId, GId};
+
+/// Log target of the audit events. Audit events are logged with the level info.
+pub const AUDIT_TARGET: &str = "tsos::audit";
+
+/// Records which secrets were provisioned for which process. Audit events never
+/// contain secret values. They are sent through the log facade with the values as
+/// key-value pairs and are optionally appended as JSON lines to an audit file. If the
+/// logger drops them, they are written to stderr as JSON lines instead.
+pub struct Audit {
+	file: Option<File>,
+	common: Vec<(&'static str, String)>
+}
+
+/// A single audit event. Fields are added while the event is processed. The event is
+/// recorded with its outcome by calling success or failure.
+#[derive(Clone)]
+pub struct Event<'a> {
+	audit: &'a Audit,
+	fields: Vec<(&'static str, String)>
+}
+
+/// Formats the fields as a single JSON object.
+fn to_json_line(time: SystemTime, fields: &[(&str, &str)]) -> String {
+	let mut line = String::from("{\"time\":");
+	write_json_string(&mut line, &rfc3339_time(time));
+	for (key, value) in fields.iter() {
+		line.push(',');
+		write_json_string(&mut line, key);
+		line.push(':');
+		write_json_string(&mut line, value);
+	}
+	line.push_str("}\n");
+
+	line
+}
+
+impl Audit {
+	/// Creates the audit trail for the process that will be executed. If an audit file
+	/// is configured it is opened for appending and created if necessary.
+	pub fn new(config_file: &Path, exec: &Path, uid: UId, gid: GId, audit_file: Option<&Path>) -> io::Result<Self> {
+		let file = match audit_file {
+			Some(audit_file) => Some(OpenOptions::new().append(true).create(true).mode(0o600).custom_flags(libc::O_NOFOLLOW).open(audit_file)?),
+			None => None
+		};
+
+		Ok(Self {
+			file,
+			common: vec![
+				("tsos_config", config_file.display().to_string()),
+				("tsos_exec", exec.display().to_string()),
+				("tsos_uid", uid.to_string()),
+				("tsos_gid", gid.to_string()),
+				("tsos_pid", std::process::id().to_string())
+			]
+		})
+	}
+
+	pub fn event(&self, name: &str) -> Event<'_> {
+		Event {
+			audit: self,
+			fields: vec![ ("tsos_event", String::from(name)) ]
+		}
+	}
+
+	fn record(&self, message: fmt::Arguments<'_>, fields: &[(&'static str, String)]) -> io::Result<()> {
+		let fields: Vec<(&str, &str)> = fields.iter().chain(self.common.iter()).map(|(key, value)| (*key, value.as_str())).collect();
+
+		// The audit trail must not depend on the log level. A logger that filters the
+		// events (like simple_logger, which only knows a global level) is bypassed.
+		let metadata = Metadata::builder().level(Level::Info).target(AUDIT_TARGET).build();
+		if log::logger().enabled(&metadata) {
+			log::logger().log(&Record::builder()
+				.args(message)
+				.metadata(metadata)
+				.module_path_static(Some(module_path!()))
+				.file_static(Some(file!()))
+				.line(Some(line!()))
+				.key_values(&fields.as_slice())
+				.build());
+		} else {
+			io::stderr().write_all(to_json_line(SystemTime::now(), &fields).as_bytes())?;
+		}
+
+		if let Some(mut file) = self.file.as_ref() {
+			// The file is opened for appending. A single write makes sure lines of
+			// concurrently running instances are not mixed up.
+			file.write_all(to_json_line(SystemTime::now(), &fields).as_bytes())?;
+		}
+
+		Ok(())
+	}
+}
+
+impl<'a> Event<'a> {
+	pub fn field<T: Display>(&mut self, key: &'static str, value: T) {
+		self.fields.push((key, value.to_string()));
+	}
+
+	/// Records that an action was started whose outcome TSoS can not observe. A later
+	/// failure event of the same name reports if it failed.
+	pub fn started(mut self, message: fmt::Arguments<'_>) -> io::Result<()> {
+		self.fields.push(("tsos_outcome", String::from("started")));
+		self.audit.record(message, &self.fields)
+	}
+
+	pub fn success(mut self, message: fmt::Arguments<'_>) -> io::Result<()> {
+		self.fields.push(("tsos_outcome", String::from("success")));
+		self.audit.record(message, &self.fields)
+	}
+
+	pub fn failure(mut self, message: fmt::Arguments<'_>, error: &dyn Display) -> io::Result<()> {
+		self.fields.push(("tsos_outcome", String::from("failure")));
+		self.fields.push(("tsos_error", error.to_string()));
+		self.audit.record(message, &self.fields)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::time::UNIX_EPOCH;
+
+	/// Verify that the audit file contains one JSON object per line.
+	#[test]
+	fn json_lines() {
+		assert_eq!(to_json_line(UNIX_EPOCH, &[ ("a", "1"), ("b", "\"q\"\n\u{1}") ]), "{\"time\":\"1970-01-01T00:00:00.000000Z\",\"a\":\"1\",\"b\":\"\\\"q\\\"\\n\\u0001\"}\n");
+	}
+}
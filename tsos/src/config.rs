@@ -1,24 +1,35 @@
 use serde::Deserialize;
 use std::path::{Path, PathBuf};
-use std::fs::File;
-use std::io::{self, Read};
+use std::fs::{File, OpenOptions};
+use std::io;
 use std::ffi::OsString;
 use std::collections::HashMap;
 use std::error;
 use std::fmt;
 use std::env::split_paths;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+
+use crate::system::{SecretBuffer, SecretString};
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
 	IoError(io::Error),
-	ParseError(toml::de::Error)
+	ParseError(toml::de::Error),
+	InsecureOwner(PathBuf, u32),
+	InsecureMode(PathBuf, u32),
+	/// The settings are valid TOML but contradict each other.
+	InvalidSetting(String)
 }
 
 impl fmt::Display for Error {
 	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
 		match self {
 			Self::IoError(error) => write!(f, "I/O error: {}", error),
-			Self::ParseError(error) => write!(f, "Prase error: {}", error)
+			Self::ParseError(error) => write!(f, "Prase error: {}", error),
+			Self::InsecureOwner(file, uid) => write!(f, "{} is owned by uid {}. It must be owned by root or the invoking user.", file.display(), uid),
+			Self::InsecureMode(file, mode) => write!(f, "{} is writable by other users (mode {:o}).", file.display(), mode & 0o7777),
+			Self::InvalidSetting(message) => write!(f, "Invalid setting: {}", message)
 		}
 	}
 }
@@ -27,7 +38,8 @@ impl error::Error for Error {
 	fn source(&self) -> Option<&(dyn error::Error + 'static)> {
 		match self {
 			Self::IoError(error) => Some(error),
-			Self::ParseError(error) => Some(error)
+			Self::ParseError(error) => Some(error),
+			_ => None
 		}
 	}
 }
@@ -51,14 +63,94 @@ pub enum Id {
 	Text(String)
 }
 
+/// Defines how symbolic links are handled if they are used as template files.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SymlinkPolicy {
+	/// Symbolic links are rejected.
+	Reject,
+	/// Symbolic links are resolved once and the resolved file is used.
+	Follow
+}
+
+/// Defines how the template and the secret file are passed to an external provider.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+	/// The paths of the template and the target file are passed as arguments.
+	Files,
+	/// The template is passed via stdin. The secret file is written to stdout.
+	Stdio
+}
+
+/// Defines how a provider that was killed by SIGKILL is handled. The kernel kills
+/// processes with SIGKILL if the system or the cgroup runs out of memory.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SigkillPolicy {
+	/// SIGKILL is handled like every other signal.
+	Fail,
+	/// TSoS fails with an error of its own (TSOS-E029).
+	Oom,
+	/// The provider is run again like after a transient exit code. If all attempts
+	/// fail, TSoS fails like with oom.
+	Retry
+}
+
+/// Retries of a secret provider within the `providers.<name>.retry` table. Delays are
+/// in seconds.
+#[derive(Debug, Deserialize)]
+pub struct RetrySettings {
+	pub attempts: Option<u32>,
+	pub delay: Option<f64>,
+	pub max_delay: Option<f64>,
+	pub exit_codes: Option<Vec<i32>>
+}
+
+/// Settings of a secret provider within the `providers` table.
+#[derive(Debug, Deserialize)]
+pub struct ProviderSettings {
+	pub executable: Option<String>,
+	pub args: Option<Vec<String>>,
+	pub env: Option<HashMap<String, SecretString>>,
+	pub timeout: Option<u64>,
+	pub protocol: Option<Protocol>,
+	pub retry: Option<RetrySettings>,
+	pub output_lines: Option<usize>,
+	pub log_stdout: Option<bool>,
+	pub on_sigkill: Option<SigkillPolicy>
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Local {
 	pub exec: PathBuf,
 	pub env_path: Option<bool>,
 	pub secrets: HashMap<String, Vec<String>>,
 	pub search_path: Option<Vec<PathBuf>>,
+	pub provider_sha256: Option<HashMap<String, String>>,
+	pub providers: Option<HashMap<String, ProviderSettings>>,
+	pub detached_mount: Option<bool>,
+	pub template_symlinks: Option<SymlinkPolicy>,
+	pub read_once: Option<Vec<String>>,
+	pub audit_file: Option<PathBuf>,
+	pub provider_landlock: Option<bool>,
+	pub provider_read: Option<Vec<PathBuf>>,
+	pub provider_write: Option<Vec<PathBuf>>,
+	pub provider_seccomp: Option<bool>,
+	pub provider_env_allow: Option<Vec<String>>,
 	pub uid: Option<Id>,
-	pub gid: Option<Id>
+	pub gid: Option<Id>,
+	pub groups: Option<Vec<Id>>,
+	pub init_groups: Option<bool>,
+	pub no_new_privs: Option<bool>,
+	pub ambient_capabilities: Option<Vec<String>>,
+	pub lock_securebits: Option<bool>,
+	pub args: Option<Vec<String>>,
+	pub chdir: Option<PathBuf>,
+	pub clear_env: Option<bool>,
+	pub env_allow: Option<Vec<String>>,
+	pub env_deny: Option<Vec<String>>,
+	pub set_env: Option<HashMap<String, SecretString>>
 }
 
 #[derive(Debug)]
@@ -68,18 +160,74 @@ pub struct Global {
 
 #[derive(Debug)]
 pub struct Config {
+	/// The configuration file the configuration was read from.
+	pub file: PathBuf,
 	pub local: Local,
 	pub global: Global
 }
 
+/// Makes sure only root or the invoking user can change the file. The file must not be
+/// writable by anybody but its owner.
+fn check_file(file: &Path, owner: u32, mode: u32, invoking_uid: u32) -> Result<(), Error> {
+	if owner != 0 && owner != invoking_uid {
+		Err(Error::InsecureOwner(PathBuf::from(file), owner))
+	} else if mode & (libc::S_IWGRP | libc::S_IWOTH) != 0 {
+		Err(Error::InsecureMode(PathBuf::from(file), mode))
+	} else {
+		Ok(())
+	}
+}
+
+/// Opens a configuration file. If TSoS runs with elevated privileges whoever can change
+/// the configuration file decides what runs with these privileges. Therefore symbolic
+/// links are not followed and the owner and mode of the file are checked. The checks
+/// can be disabled for development by enabling the insecure-config feature.
+fn open_config(file: &Path) -> Result<File, Error> {
+	if cfg!(feature = "insecure-config") || !crate::system::is_privileged()? {
+		return Ok(File::open(file)?);
+	}
+
+	let config_file = OpenOptions::new().read(true).custom_flags(libc::O_NOFOLLOW).open(file)?;
+	let metadata = config_file.metadata()?;
+	check_file(file, metadata.uid(), metadata.mode(), unsafe { libc::getuid() })?;
+
+	Ok(config_file)
+}
+
+impl Local {
+	/// Rejects settings that would be silently ignored.
+	fn validate(&self) -> Result<(), Error> {
+		if self.clear_env.unwrap_or(false) && self.env_allow.is_some() {
+			return Err(Error::InvalidSetting(String::from("clear_env and env_allow can not be combined. env_allow already passes only the listed variables.")));
+		}
+
+		for template in self.read_once.iter().flatten() {
+			if !self.secrets.values().flatten().any(|v| v == template) {
+				return Err(Error::InvalidSetting(format!("read_once file {} is not a template within the secrets section.", template)));
+			}
+		}
+
+		for (name, settings) in self.providers.iter().flatten() {
+			let attempts = settings.retry.as_ref().and_then(|v| v.attempts).unwrap_or(1);
+			if settings.on_sigkill == Some(SigkillPolicy::Retry) && attempts < 2 {
+				return Err(Error::InvalidSetting(format!("on_sigkill = \"retry\" of provider {} requires retry.attempts to be at least 2.", name)));
+			}
+		}
+
+		Ok(())
+	}
+}
+
 /// WARNING: This class must be prepared to vanish at any moment without getting
 /// its destructor called. Do not use resources thar require RAII!
 impl Config {
 	pub fn new(file: &Path, env_path: Option<OsString>) -> Result<Self, Error> {
-		// Read and parse the configuration file
-		let mut config_string = String::new();
-		File::open(file)?.read_to_string(&mut config_string)?;
-		let local_config: Local = toml::from_str(&config_string)?;
+		// Read and parse the configuration file. It may contain secrets (e.g. within
+		// set_env) therefore it is read into locked memory that is wiped afterwards.
+		// The values of set_env and the provider env are kept in locked memory too.
+		let config_data = SecretBuffer::read_from(&mut open_config(file)?)?;
+		let local_config: Local = toml::from_str(config_data.as_str()?)?;
+		local_config.validate()?;
 
 		// Create a list of search paths used for searching for secret provider scripts
 		let mut search_path = Vec::with_capacity(2);
@@ -99,6 +247,7 @@ impl Config {
 		search_path.push(PathBuf::from("/usr/lib/tsos"));
 
 		Ok(Self{
+			file: PathBuf::from(file),
 			local: local_config,
 			global: Global {
 				search_path
@@ -107,10 +256,33 @@ impl Config {
 	}
 }
 
+#[allow(clippy::assertions_on_constants)]
 #[cfg(test)]
 mod test {
 	use super::*;
 
+	/// Verify that only files changeable by root or the invoking user are accepted.
+	#[test]
+	fn check_owner_mode() {
+		let file = Path::new("test.toml");
+
+		assert!(check_file(file, 0, 0o100644, 1000).is_ok());
+		assert!(check_file(file, 1000, 0o100600, 1000).is_ok());
+
+		match check_file(file, 1001, 0o100644, 1000) {
+			Err(Error::InsecureOwner(_, 1001)) => (),
+			_ => panic!("File of another user was accepted.")
+		}
+		match check_file(file, 0, 0o100664, 1000) {
+			Err(Error::InsecureMode(_, 0o100664)) => (),
+			_ => panic!("Group writable file was accepted.")
+		}
+		match check_file(file, 0, 0o100646, 0) {
+			Err(Error::InsecureMode(_, 0o100646)) => (),
+			_ => panic!("World writable file was accepted.")
+		}
+	}
+
 	/// Check that the exec parameter is required
 	#[test]
 	#[should_panic(expected="missing field `exec`")]
@@ -168,10 +340,17 @@ mod test {
 			gid = "group"
 			search_path = [ "/a", "/b" ]
 			env_path = true
+			detached_mount = true
+			template_symlinks = "reject"
+			read_once = [ "/fb" ]
+			audit_file = "/var/log/tsos.jsonl"
 
 			[secrets]
 				first = [ "/fa", "/fb" ]
 				second = [ "/sa", "/sb" ]
+
+			[provider_sha256]
+				first = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
 		"#;
 		let parsed: Local = toml::from_str(toml).unwrap();
 
@@ -195,5 +374,201 @@ mod test {
 
 		// Check env_path
 		assert!(parsed.env_path.unwrap());
+
+		// Check detached_mount
+		assert!(parsed.detached_mount.unwrap());
+
+		// Check template_symlinks
+		assert_eq!(parsed.template_symlinks.unwrap(), SymlinkPolicy::Reject);
+
+		// Check read_once
+		assert_eq!(parsed.read_once.unwrap(), vec![ "/fb" ]);
+
+		// Check audit_file
+		assert_eq!(parsed.audit_file.unwrap(), PathBuf::from("/var/log/tsos.jsonl"));
+
+		// Check provider_sha256
+		assert_eq!(parsed.provider_sha256.unwrap().get("first").unwrap(), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+	}
+
+	/// Verify that read once files must be templates.
+	#[test]
+	fn check_read_once() {
+		let parse = |read_once: &str| toml::from_str::<Local>(&format!(r#"
+			exec = "test"
+			read_once = [ "{}" ]
+
+			[secrets]
+			vault = [ "/etc/db.conf" ]
+		"#, read_once)).unwrap();
+
+		assert!(parse("/etc/db.conf").validate().is_ok());
+		match parse("/etc/other.conf").validate() {
+			Err(Error::InvalidSetting(message)) => assert!(message.contains("/etc/other.conf")),
+			_ => panic!("Unknown read once file was not rejected.")
+		}
+	}
+
+	/// Verify that on_sigkill = "retry" is rejected without retries.
+	#[test]
+	fn check_sigkill_retry() {
+		let parse = |retry: &str| toml::from_str::<Local>(&format!(r#"
+			exec = "test"
+
+			[secrets]
+			vault = [ "/etc/db.conf" ]
+
+			[providers.vault]
+			on_sigkill = "retry"
+			{}
+		"#, retry)).unwrap();
+
+		assert!(matches!(parse("").validate(), Err(Error::InvalidSetting(_))));
+		assert!(matches!(parse("retry = { attempts = 1 }").validate(), Err(Error::InvalidSetting(_))));
+		assert!(parse("retry = { attempts = 3 }").validate().is_ok());
+	}
+
+	/// Verify that the provider settings are parsed correctly.
+	#[test]
+	fn check_providers() {
+		let toml = r#"
+			exec = "test"
+
+			[secrets]
+			vault_db = [ "/etc/db.conf" ]
+			vault_web = [ "/etc/web.conf" ]
+
+			[providers.vault_db]
+			executable = "vault"
+			args = [ "--role", "db" ]
+			env = { VAULT_ADDR = "https://vault:8200" }
+			timeout = 30
+			protocol = "stdio"
+			on_sigkill = "retry"
+			log_stdout = true
+
+			[providers.vault_db.retry]
+			attempts = 5
+			delay = 0.5
+			exit_codes = [ 75, 69 ]
+
+			[providers.vault_web]
+		"#;
+		let parsed: Local = toml::from_str(toml).unwrap();
+		let providers = parsed.providers.unwrap();
+
+		let db = providers.get("vault_db").unwrap();
+		assert_eq!(db.executable.as_deref(), Some("vault"));
+		assert_eq!(db.args.as_ref().unwrap(), &vec![ "--role", "db" ]);
+		assert_eq!(db.env.as_ref().unwrap().get("VAULT_ADDR").map(|v| &**v).unwrap(), "https://vault:8200");
+		assert_eq!(db.timeout, Some(30));
+		assert_eq!(db.protocol, Some(Protocol::Stdio));
+		assert_eq!(db.on_sigkill, Some(SigkillPolicy::Retry));
+		assert_eq!(db.log_stdout, Some(true));
+		let retry = db.retry.as_ref().unwrap();
+		assert_eq!(retry.attempts, Some(5));
+		assert_eq!(retry.delay, Some(0.5));
+		assert_eq!(retry.max_delay, None);
+		assert_eq!(retry.exit_codes.as_ref().unwrap(), &vec![ 75, 69 ]);
+
+		let web = providers.get("vault_web").unwrap();
+		assert!(web.executable.is_none() && web.args.is_none() && web.env.is_none() && web.timeout.is_none() && web.protocol.is_none() && web.retry.is_none() && web.on_sigkill.is_none());
+	}
+
+	/// Verify that the provider sandbox options are parsed correctly.
+	#[test]
+	fn check_provider_sandbox() {
+		let toml = r#"
+			exec = "test"
+			provider_landlock = true
+			provider_read = [ "/usr", "/etc/ssl" ]
+			provider_write = [ "/run/provider" ]
+			provider_seccomp = true
+			provider_env_allow = [ "VAULT_ADDR" ]
+
+			[secrets]
+		"#;
+		let parsed: Local = toml::from_str(toml).unwrap();
+
+		assert!(parsed.provider_landlock.unwrap());
+		assert_eq!(parsed.provider_read.unwrap(), vec![ PathBuf::from("/usr"), PathBuf::from("/etc/ssl") ]);
+		assert_eq!(parsed.provider_write.unwrap(), vec![ PathBuf::from("/run/provider") ]);
+		assert!(parsed.provider_seccomp.unwrap());
+		assert_eq!(parsed.provider_env_allow.unwrap(), vec![ "VAULT_ADDR" ]);
+	}
+
+	/// Verify that the hardening options are parsed correctly.
+	#[test]
+	fn check_hardening() {
+		let toml = r#"
+			exec = "test"
+			no_new_privs = true
+			ambient_capabilities = [ "CAP_NET_BIND_SERVICE" ]
+			lock_securebits = true
+
+			[secrets]
+		"#;
+		let parsed: Local = toml::from_str(toml).unwrap();
+
+		assert!(parsed.no_new_privs.unwrap());
+		assert_eq!(parsed.ambient_capabilities.unwrap(), vec![ "CAP_NET_BIND_SERVICE" ]);
+		assert!(parsed.lock_securebits.unwrap());
+	}
+
+	/// Verify that the supplementary group list accepts names and nummeric ids.
+	#[test]
+	fn check_groups() {
+		let toml = r#"
+			exec = "test"
+			groups = [ "first", 20 ]
+			init_groups = false
+
+			[secrets]
+		"#;
+		let parsed: Local = toml::from_str(toml).unwrap();
+
+		let mut groups = parsed.groups.unwrap();
+		match groups.remove(0) {
+			Id::Text(group) => assert_eq!(group, "first"),
+			_ => panic!("First group is not text.")
+		}
+		match groups.remove(0) {
+			Id::Nummeric(gid) => assert_eq!(gid, 20),
+			_ => panic!("Second group is not nummeric.")
+		}
+		assert!(!parsed.init_groups.unwrap());
+	}
+
+	/// Verify that the options describing how the executable is launched are parsed correctly.
+	#[test]
+	fn check_launch_options() {
+		let toml = r#"
+			exec = "test"
+			args = [ "-a", "--b" ]
+			chdir = "/tmp"
+			clear_env = true
+			env_allow = [ "PATH", "LANG" ]
+			env_deny = [ "TSOS_LOG" ]
+
+			[set_env]
+				FIRST = "1"
+				SECOND = "t\u0077o"
+
+			[secrets]
+		"#;
+		let parsed: Local = toml::from_str(toml).unwrap();
+
+		// env_allow would be ignored with clear_env.
+		assert!(matches!(parsed.validate(), Err(Error::InvalidSetting(_))));
+
+		assert_eq!(parsed.args.unwrap(), vec![ "-a", "--b" ]);
+		assert_eq!(parsed.chdir.unwrap().to_string_lossy(), "/tmp");
+		assert!(parsed.clear_env.unwrap());
+		assert_eq!(parsed.env_allow.unwrap(), vec![ "PATH", "LANG" ]);
+		assert_eq!(parsed.env_deny.unwrap(), vec![ "TSOS_LOG" ]);
+
+		let set_env = parsed.set_env.unwrap();
+		assert_eq!(set_env.get("FIRST").map(|v| &**v).unwrap(), "1");
+		assert_eq!(set_env.get("SECOND").map(|v| &**v).unwrap(), "two");
 	}
 }
\ No newline at end of file
@@ -0,0 +1,175 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+use crate::config;
+use crate::session::ProvisionError;
+use crate::system;
+
+/// All failures of TSoS. Every error has a stable code (e.g. `TSOS-E025`). The number
+/// of the code is the exit status of the `tsos` binary. Codes are grouped by their
+/// tens: 1x configuration, 2x secret providers, 3x system, 4x users and privileges,
+/// 5x audit and 6x exec. Codes are never reused for other failures.
+#[derive(Debug)]
+pub enum Error {
+	/// The configuration file was not passed on the command line.
+	MissingConfig,
+	Config(config::Error),
+	Provision(ProvisionError),
+	System(system::Error),
+	/// Writing the audit trail failed.
+	Audit(io::Error),
+	/// Replacing the TSoS process with the executable failed.
+	Exec(io::Error),
+	/// Errors without a code of their own. Other I/O errors are reported as system errors.
+	Other(Box<dyn error::Error>)
+}
+
+impl Error {
+	/// The number of the error code. It is used as the exit status of TSoS.
+	pub fn number(&self) -> u8 {
+		match self {
+			Self::MissingConfig => 1,
+			Self::Config(error) => match error {
+				config::Error::IoError(_) => 10,
+				config::Error::ParseError(_) => 11,
+				config::Error::InsecureOwner(_, _) => 12,
+				config::Error::InsecureMode(_, _) => 13,
+				config::Error::InvalidSetting(_) => 14
+			},
+			Self::Provision(error) => match error {
+				ProvisionError::ProviderNotFound(_) => 20,
+				ProvisionError::InvalidSourceName(_) => 21,
+				ProvisionError::ProviderNoFile(_) => 22,
+				ProvisionError::ProviderHashMismatch(_, _, _) => 23,
+				ProvisionError::TemplateNotFound(_, _) => 24,
+				ProvisionError::ProviderFailed(_, _, _) => 25,
+				ProvisionError::ProviderTerminated(_, _, _, _, _) => 26,
+				ProvisionError::ProviderTimeout(_, _) => 27,
+				ProvisionError::ProviderError(_, _) => 28,
+				ProvisionError::ProviderKilled(_, _, _) => 29
+			},
+			Self::System(error) => match error {
+				system::Error::OsError(_) => 30,
+				system::Error::ConversionError(_) => 31,
+				system::Error::InvalidString(_) => 32,
+				system::Error::SymlinkRejected(_) => 33,
+				system::Error::NotAFile(_) => 34,
+				system::Error::FileReplaced(_) => 35,
+				system::Error::SandboxNotPrepared => 36,
+				system::Error::Unsupported(_) => 37,
+				system::Error::ExecFailed(_) => 38,
+				system::Error::UserNotFound(_) => 40,
+				system::Error::GroupNotFound(_) => 41,
+				system::Error::CapabilityNotFound(_) => 42,
+				system::Error::CapabilityFailed(_, _) => 43
+			},
+			Self::Audit(_) => 50,
+			Self::Exec(_) => 60,
+			Self::Other(_) => 99
+		}
+	}
+
+	/// The stable error code (e.g. `TSOS-E025`).
+	pub fn code(&self) -> String {
+		format!("TSOS-E{:03}", self.number())
+	}
+
+	pub fn exit_status(&self) -> i32 {
+		self.number() as i32
+	}
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::MissingConfig => write!(f, "Missing configuration file command line parameter."),
+			Self::Config(error) => write!(f, "Failed to parse configuration file {}", error),
+			Self::Provision(error) => write!(f, "{}", error),
+			Self::System(error) => write!(f, "{}", error),
+			Self::Audit(error) => write!(f, "Writing audit event failed: {}", error),
+			Self::Exec(error) => write!(f, "{}", error),
+			Self::Other(error) => write!(f, "{}", error)
+		}
+	}
+}
+
+impl error::Error for Error {
+	fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+		match self {
+			Self::MissingConfig => None,
+			Self::Config(error) => Some(error),
+			Self::Provision(error) => Some(error),
+			Self::System(error) => Some(error),
+			Self::Audit(error) => Some(error),
+			Self::Exec(error) => Some(error),
+			Self::Other(error) => Some(error.as_ref())
+		}
+	}
+}
+
+impl From<config::Error> for Error {
+	fn from(error: config::Error) -> Self {
+		Self::Config(error)
+	}
+}
+
+impl From<ProvisionError> for Error {
+	fn from(error: ProvisionError) -> Self {
+		Self::Provision(error)
+	}
+}
+
+impl From<system::Error> for Error {
+	fn from(error: system::Error) -> Self {
+		Self::System(error)
+	}
+}
+
+impl From<io::Error> for Error {
+	fn from(error: io::Error) -> Self {
+		Self::System(system::Error::OsError(error))
+	}
+}
+
+/// The preparation passes errors of all modules on as boxed errors. The known error
+/// types are unboxed to get their code.
+impl From<Box<dyn error::Error>> for Error {
+	fn from(error: Box<dyn error::Error>) -> Self {
+		let error = match error.downcast::<Error>() { Ok(error) => return *error, Err(error) => error };
+		let error = match error.downcast::<ProvisionError>() { Ok(error) => return Self::Provision(*error), Err(error) => error };
+		let error = match error.downcast::<system::Error>() { Ok(error) => return Self::System(*error), Err(error) => error };
+		let error = match error.downcast::<config::Error>() { Ok(error) => return Self::Config(*error), Err(error) => error };
+		match error.downcast::<io::Error>() {
+			Ok(error) => Self::from(*error),
+			Err(error) => Self::Other(error)
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::path::PathBuf;
+
+	/// Verify that boxed errors keep their code and that codes match the exit status.
+	#[test]
+	fn error_codes() {
+		let error = Error::from(Box::new(ProvisionError::ProviderFailed(PathBuf::from("/bin/false"), 1, Vec::new())) as Box<dyn error::Error>);
+		assert_eq!(error.code(), "TSOS-E025");
+		assert_eq!(error.exit_status(), 25);
+
+		let error = Error::from(Box::new(system::Error::GroupNotFound(String::from("nobody"))) as Box<dyn error::Error>);
+		assert_eq!(error.code(), "TSOS-E041");
+
+		let error = Error::from(Box::new(io::Error::from(io::ErrorKind::NotFound)) as Box<dyn error::Error>);
+		assert_eq!(error.code(), "TSOS-E030");
+
+		let error = Error::from(Box::new(Error::Audit(io::Error::from(io::ErrorKind::Other))) as Box<dyn error::Error>);
+		assert_eq!(error.code(), "TSOS-E050");
+
+		let error = Error::from(Box::<dyn error::Error>::from("other"));
+		assert_eq!(error.code(), "TSOS-E099");
+		assert_eq!(Error::MissingConfig.code(), "TSOS-E001");
+	}
+}
@@ -0,0 +1,18 @@
+//! TSoS provisions secrets for a process. The secrets are created by secret providers
+//! and bound onto template files within a private mount namespace.
+//!
+//! Use [`Session`] to embed TSoS into another launcher. The `tsos` binary is a thin
+//! command line interface on top of it.
+
+mod system;
+pub mod audit;
+pub mod config;
+mod session;
+mod provider;
+mod error;
+mod output;
+
+pub use session::{Session, Prepared, ProvisionError};
+pub use error::Error;
+pub use provider::{SecretProvider, ProviderContext, ProviderRegistry};
+pub use system::Error as SystemError;
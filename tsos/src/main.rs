@@ -1,197 +1,152 @@
-use std::path::{Path, PathBuf};
-use std::error;
-use std::process::Command;
-use std::env;
+use std::path::PathBuf;
 use std::os::unix::process::CommandExt;
-use std::fmt;
+use std::env;
 use std::str::FromStr;
-use std::ffi::OsStr;
 use std::process::exit;
+use std::fmt;
 
-use simple_logger;
-use log::{Level, debug, info, error};
+use log::{Level, debug, info, warn, error};
+#[cfg(any(feature = "systemd", feature = "syslog", feature = "json"))]
+use log::LevelFilter;
+#[cfg(any(feature = "systemd", feature = "syslog", feature = "json"))]
+use tsos::audit::AUDIT_TARGET;
 
-mod system;
-use system::TempDir;
+use tsos::{Session, Error};
+use tsos::config::Config;
 
-mod config;
-use config::Config;
-use config::Id;
+/// Returns the default level of the log directives. simple_logger does not support
+/// levels per module.
+fn default_level(log_directives: &str) -> Level {
+	log_directives.rsplit(',').find_map(|v| Level::from_str(v.trim()).ok()).unwrap_or(Level::Warn)
+}
 
-#[derive(Debug)]
-pub enum Error {
-	ProviderNotFound(String),
-	ProviderFailed(PathBuf, i32),
-	ProviderTerminated(PathBuf),
-	ProviderNoFile(PathBuf),
-	TemplateNotFound(String, String),
-	InvalidSourceName(String),
+/// Outputs TSoS can log to. Selected via the TSOS_LOG_TARGET environment variable.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LogTarget {
+	Journal,
+	Syslog,
+	Json,
+	Stderr
 }
 
-impl fmt::Display for Error {
-	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-		match self {
-			Self::ProviderNotFound(sos) => write!(f, "Provider {} not found in search path.", sos),
-			Self::ProviderFailed(provider_file, result_code) => write!(f, "Provider {} failed to execute with result code {}.", provider_file.display(), result_code),
-			Self::ProviderTerminated(provider_file) => write!(f, "Provider {} terminated by signal.", provider_file.display()),
-			Self::ProviderNoFile(provider_file) => write!(f, "{} is not a file.", provider_file.display()),
-			Self::TemplateNotFound(sos, source_file) => write!(f, "Template file {} for secret provider {} not found.", source_file, sos),
-			Self::InvalidSourceName(sos) => write!(f, "Invalid source name {}.", sos)
+impl FromStr for LogTarget {
+	type Err = String;
+
+	fn from_str(value: &str) -> Result<Self, String> {
+		match value.trim().to_ascii_lowercase().as_str() {
+			"journal" => Ok(Self::Journal),
+			"syslog" => Ok(Self::Syslog),
+			"json" => Ok(Self::Json),
+			"stderr" => Ok(Self::Stderr),
+			_ => Err(format!("Unknown log target \"{}\".", value))
 		}
 	}
 }
 
-impl error::Error for Error {
-	fn source(&self) -> Option<&(dyn error::Error + 'static)> {
-		None
-	}
+/// Returns true if the value of the environment variable starts with 1, y or t.
+#[cfg(feature = "systemd")]
+fn env_flag(name: &str) -> bool {
+	env::var(name).ok().and_then(|v| v.chars().next()).map(|v| [ '1', 'y', 'Y', 't', 'T' ].contains(&v)).unwrap_or(false)
 }
 
-/// Variant of start_logger that tries to detect an active jourald an
-/// switches to journal logging if it is enabled.
+/// Used if TSOS_LOG_TARGET is not set. Switches to journal logging if an active
+/// journald is detected or TSOS_FORCE_JOURNAL is set.
 #[cfg(feature = "systemd")]
-fn start_logger(log_level: Level) {
-	let force = if let Ok(force_env) = env::var("TSOS_FORCE_JOURNAL") {
-		if let Some(first_char) = force_env.chars().next() {
-			[ '1', 'y', 'Y', 't', 'T' ].iter().any(|&v| v == first_char)
-		} else {
-			false
-		}
-	} else {
-		false
-	};
-
-	if force || journal_logger::has_journal() {
-		journal_logger::init_with_level(log_level).unwrap();
-		debug!("Journal logging detected. Switch to journal logger completed.");
-	} else {
-		simple_logger::init_with_level(log_level).unwrap();
-		debug!("No journal logging detected. Using default stderr logger.");
-	}
+fn detect_log_target() -> LogTarget {
+	if env_flag("TSOS_FORCE_JOURNAL") || journal_logger::has_journal() { LogTarget::Journal } else { LogTarget::Stderr }
 }
 
-/// Variant of start_logger that always uses simple_logger if the systemd feature
+/// Variant of detect_log_target that always uses stderr if the systemd feature
 /// is disabled.
 #[cfg(not(feature = "systemd"))]
-fn start_logger(log_level: Level) {
-	simple_logger::init_with_level(log_level).unwrap();
+fn detect_log_target() -> LogTarget {
+	LogTarget::Stderr
 }
 
-fn find_provider(search_path: &[PathBuf], provider_name: &OsStr) -> Option<PathBuf> {
-	for path in search_path {
-		if path.is_dir() {
-			let mut provider_path = PathBuf::from(path);
-			provider_path.push(provider_name);
-
-			debug!("Trying {} as secret provider...", provider_path.display());
-
-			//TODO: Check the file is only writable by root.
+/// Starts the logger of the log target. The fields are added to every journal entry,
+/// syslog message and JSON line. Targets that are not supported by this build fall back
+/// to stderr. The audit events are logged regardless of the level.
+#[cfg_attr(not(any(feature = "systemd", feature = "syslog", feature = "json")), allow(unused_variables))]
+fn start_logger(log_directives: &str, fields: &[(&str, String)]) {
+	let (target, invalid) = match env::var("TSOS_LOG_TARGET") {
+		Ok(ref value) if !value.trim().is_empty() => match LogTarget::from_str(value) {
+			Ok(target) => (target, None),
+			Err(error) => (detect_log_target(), Some(error))
+		},
+		_ => (detect_log_target(), None)
+	};
 
-			if provider_path.is_file() {
-				return Some(provider_path);
+	match target {
+		#[cfg(feature = "systemd")]
+		LogTarget::Journal => {
+			// Messages that can not be sent to the journal are written to stderr.
+			let builder = journal_logger::Builder::new()
+				.level(LevelFilter::Warn)
+				.filter(AUDIT_TARGET, LevelFilter::Info)
+				.parse(log_directives)
+				.identifier(env!("CARGO_PKG_NAME"))
+				.fallback_stderr(true);
+			fields.iter().fold(builder, |builder, (key, value)| builder.field(key, value)).init().unwrap();
+			debug!("Switch to journal logger completed.");
+		},
+		#[cfg(feature = "syslog")]
+		LogTarget::Syslog => {
+			// Messages that can not be sent to the syslog daemon are written to stderr.
+			let format = match env::var("TSOS_SYSLOG_FORMAT") {
+				Ok(ref value) if value.eq_ignore_ascii_case("rfc3164") => journal_logger::SyslogFormat::Rfc3164,
+				_ => journal_logger::SyslogFormat::Rfc5424
+			};
+			let builder = journal_logger::SyslogBuilder::new()
+				.level(LevelFilter::Warn)
+				.filter(AUDIT_TARGET, LevelFilter::Info)
+				.parse(log_directives)
+				.identifier(env!("CARGO_PKG_NAME"))
+				.format(format)
+				.fallback_stderr(true);
+			fields.iter().fold(builder, |builder, (key, value)| builder.field(key, value)).init().unwrap();
+			debug!("Switch to syslog logger completed.");
+		},
+		#[cfg(feature = "json")]
+		LogTarget::Json => {
+			let builder = journal_logger::JsonBuilder::new()
+				.level(LevelFilter::Warn)
+				.filter(AUDIT_TARGET, LevelFilter::Info)
+				.parse(log_directives);
+			fields.iter().fold(builder, |builder, (key, value)| builder.field(key, value)).init().unwrap();
+			debug!("Switch to JSON logger completed.");
+		},
+		_ => {
+			simple_logger::init_with_level(default_level(log_directives)).unwrap();
+			if target != LogTarget::Stderr {
+				warn!("Log target {:?} is not supported by this build. Logging to stderr.", target);
 			}
-		} else {
-			debug!("Search path {} not found or no directory.", path.display());
 		}
 	}
 
-	None
-}
-
-fn prepare(config: &Config) -> Result<(), Box<dyn error::Error>> {
-	system::unshare_mount_ns()?;
-
-	// Create temporary directory and mount a ramfs onto it
-	let mut temp = TempDir::new("tsos")?;
-	
-	let _temp_mount = system::RamFs::new("tsos", temp.as_ref())?;
-
-	for (sos, templates) in config.local.secrets.iter() {
-		debug!("Processing secret provider {}...", sos);
-
-		// Make sure the file name can not be used for path traversal attacks
-		let sos = Path::new(sos).file_name().ok_or_else(|| Error::InvalidSourceName(sos.clone()))?;
-
-		// Search for the secret provider
-		// If a local search path is configured it takes precedence over the global search path.
-		let mut provider_search_result = if let Some(ref search_path) = config.local.search_path { find_provider(search_path, sos) } else { None };
-		if provider_search_result.is_none() {
-			provider_search_result = find_provider(&config.global.search_path, sos);
-		}
-
-		if let Some(provider_file) = provider_search_result {
-			debug!("Found secret provider {} for secret {}.", provider_file.display(), sos.to_string_lossy());
-			for template in templates.iter() {
-				let target = temp.create_file("tsos-final")?;
-				let template = Path::new(template);	// Shadow target with a path instance because we need a path more often than a string.
-
-				if template.is_file() {
-					debug!("Executing secret provider...");
-
-					// Execute the secret provider.
-					// It will use the input file ($1) and update the output file ($2).
-					let exit_code = Command::new(&provider_file).args(&[template, &target]).status()?;
-					if !exit_code.success() {
-						if let Some(code) = exit_code.code() {
-							return Err(Box::new(Error::ProviderFailed(provider_file, code)));
-						} else {
-							return Err(Box::new(Error::ProviderTerminated(provider_file)));
-						}
-					}
-
-					debug!("Copying permissions...");
-
-					system::copy_perms_and_owners(&template, &target)?;
-
-					system::bind(&target, &template)?;
-				} else {
-					return Err(Box::new(Error::TemplateNotFound(sos.to_string_lossy().into_owned(), template.to_string_lossy().into_owned())));
-				}
-			}
-		} else {
-			return Err(Box::new(Error::ProviderNotFound(sos.to_string_lossy().into_owned())));
-		}
+	if let Some(error) = invalid {
+		warn!("{} Using {:?} instead.", error, target);
 	}
-
-	Ok(())
 }
 
-fn prepare_privileges(command: &mut Command, config: &Config) -> Result<(), Box<dyn error::Error>> {
-	let uid_gid = match config.local.uid {
-		Some(Id::Nummeric(ref uid)) => Some(system::resolve_uid(*uid)?),
-		Some(Id::Text(ref user_name)) => Some(system::resolve_user(user_name)?),
-		None => None
-	};
-	let gid = match config.local.gid {
-		Some(Id::Nummeric(ref gid)) => Some(*gid),
-		Some(Id::Text(ref group_name)) => Some(system::resolve_group(group_name)?),
-		None => None
-	};
-
-	if let Some((uid, ugid)) = uid_gid { command.uid(uid); command.gid(ugid); }
-	if let Some(gid) = gid { command.gid(gid); }
-	
-	Ok(())
+/// Logs the error with its code and exits with the exit status of the error. The code
+/// is passed as the field TSOS_ERROR_CODE too.
+fn fail(context: fmt::Arguments<'_>, error: Error) -> ! {
+	let code = error.code();
+	error!(tsos_error_code = code.as_str(); "{}{} ({})", context, error, code);
+	exit(error.exit_status());
 }
 
 /// WARNING: This function ends in an execvp. No destructors for instances allocated
 /// within this function will run. All preparation is done in the prepare() function.
 /// When this function terminates all destructors (drop) will run and everything is
-/// fine. Therefore do all RAII within prepare!
-/// This method only allocates a Logger- and a Config-Instance. These will not been
+/// fine. Therefore do all RAII within prepare and drop the guard it returns!
+/// This method only allocates a Logger- and a Session-Instance. These will not been
 /// torn down. They simply will vanish when the process memory is replaced with the
 /// new process image.
 fn main() {
-	// Parse the TSOS_LOG environment variable and set the log-level accoringly.
-	let log_level = match env::var("TSOS_LOG") {
-		Ok(level) => Level::from_str(&level).unwrap_or(Level::Warn),
-		Err(_) => Level::Warn
-	};
-
-	start_logger(log_level);
-
-	// Output some version information
-	info!("{} v{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+	// The TSOS_LOG environment variable contains the log level or filter directives
+	// (e.g. `info,tsos::system=debug`).
+	let log_directives = env::var("TSOS_LOG").unwrap_or_default();
 
 	// Extract the command line arguments and check if we got at least one
 	// argument (the config file name). All other arguments will be passed
@@ -199,31 +154,64 @@ fn main() {
 	let mut args: Vec<String> = env::args().collect();
 	args.remove(0); // Remove the first argument as it is our name.
 
-	if args.is_empty() {
-		error!("Missing configuration file command line parameter.");
-		exit(1);
+	// The configuration is read before the logger is started. That way every journal
+	// entry carries the configuration file and the executable.
+	let config_file = if args.is_empty() { None } else { Some(PathBuf::from(args.remove(0))) };
+	let config = config_file.as_ref().map(|v| Config::new(v, std::env::var_os("TSOS_PATH")));
+
+	let mut fields = Vec::with_capacity(2);
+	if let Some(ref config_file) = config_file { fields.push(("TSOS_CONFIG", config_file.display().to_string())); }
+	if let Some(Ok(ref config)) = config { fields.push(("TSOS_EXEC", config.local.exec.display().to_string())); }
+
+	start_logger(&log_directives, &fields);
+
+	// Output some version information
+	info!("{} v{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+
+	let config = match config {
+		Some(Ok(config)) => config,
+		Some(Err(error)) => fail(format_args!(""), Error::Config(error)),
+		None => fail(format_args!(""), Error::MissingConfig)
+	};
+
+	let exec = config.local.exec.clone();
+	let mut session = match Session::from_config(config) {
+		Ok(session) => session,
+		Err(error) => fail(format_args!("Preparing privileges for executing {} failed: ", exec.display()), error)
+	};
+
+	// The guard is dropped right away. It must not outlive the exec below.
+	if let Err(error) = session.prepare() {
+		fail(format_args!("Starting {} with TSOS failed: ", exec.display()), error);
 	}
 
-	match Config::new(&PathBuf::from(args.remove(0)), std::env::var_os("TSOS_PATH")) {
-		Ok(config) => {
-			if let Err(error) = prepare(&config) {
-				error!("Starting {} with TSOS failed: {}", config.local.exec.display(), error);
-				exit(3);
-			}
+	debug!("Replacing this process with {}...", exec.display());
 
-			debug!("Replacing this process with {}...", config.local.exec.display());
+	let mut command = match session.command() {
+		Ok(command) => command,
+		Err(error) => fail(format_args!("Preparing privileges for executing {} failed: ", exec.display()), error)
+	};
+	command.args(args);
 
-			let mut command = Command::new(&config.local.exec);
-			if let Err(error) = prepare_privileges(&mut command, &config) {
-				error!("Preparing privileges for executing {} failed: {}", config.local.exec.display(), error);
-				exit(4);
-			}
-			command.args(args);
-			command.exec();
-		},
-		Err(error) => {
-			error!("Failed to parse configuration file {} ", error);
-			exit(2);
+	// This process becomes the service by exec. The supervisor watches it.
+	if let Err(error) = session.supervise_read_once(std::process::id()) {
+		fail(format_args!("Starting {} with TSOS failed: ", exec.display()), error);
+	}
+
+	if let Some(audit) = session.audit() {
+		if let Err(error) = audit.event("exec").started(format_args!("Executing {}.", exec.display())) {
+			fail(format_args!(""), Error::Audit(error));
 		}
 	}
+
+	// exec only returns if replacing the process failed. A failure of setting
+	// the capabilities is reported with the name of the capability.
+	let error = command.exec();
+	let audit = session.audit();
+	if let Some(failure) = session.capability_failure() {
+		if let Some(audit) = audit { let _ = audit.event("exec").failure(format_args!("Executing {} failed.", exec.display()), &failure); }
+		fail(format_args!("Preparing privileges for executing {} failed: ", exec.display()), Error::System(failure));
+	}
+	if let Some(audit) = audit { let _ = audit.event("exec").failure(format_args!("Executing {} failed.", exec.display()), &error); }
+	fail(format_args!("Executing {} failed: ", exec.display()), Error::Exec(error));
 }
@@ -0,0 +1,167 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use log::{log, Level};
+
+/// Log target of the output of the secret providers.
+pub const OUTPUT_TARGET: &str = "tsos::provider";
+
+/// Logs the output of a secret provider line by line and keeps the last lines for the
+/// error message. Every pipe is read within its own thread. The threads are stopped and
+/// joined by finish (or if it is dropped). None of them outlives the provider.
+pub struct ProviderOutput {
+	name: String,
+	max_lines: usize,
+	lines: Arc<Mutex<VecDeque<String>>>,
+	done_sender: mpsc::Sender<()>,
+	done: mpsc::Receiver<()>,
+	readers: Vec<JoinHandle<()>>,
+	// Closing the write end wakes up all readers and makes them stop.
+	stop_reader: File,
+	stop_writer: Option<File>
+}
+
+impl Drop for ProviderOutput {
+	fn drop(&mut self) {
+		self.stop();
+	}
+}
+
+/// Logs a line of the provider and keeps it within the last lines.
+fn record(name: &str, stream: &str, level: Level, max_lines: usize, lines: &Mutex<VecDeque<String>>, line: &[u8]) {
+	// Providers may write anything. Invalid UTF-8 is replaced.
+	let line = String::from_utf8_lossy(line).trim_end_matches(['\n', '\r']).to_owned();
+	log!(target: OUTPUT_TARGET, level, tsos_provider = name, tsos_stream = stream; "{}: {}", name, line);
+
+	if max_lines > 0 {
+		if let Ok(mut lines) = lines.lock() {
+			if lines.len() == max_lines { lines.pop_front(); }
+			lines.push_back(line);
+		}
+	}
+}
+
+impl ProviderOutput {
+	/// Captures the output of the provider with the name. The last max_lines lines are kept.
+	pub fn new(name: &str, max_lines: usize) -> io::Result<Self> {
+		let mut fds = [0 as RawFd; 2];
+		if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+			return Err(io::Error::last_os_error());
+		}
+		let (done_sender, done) = mpsc::channel();
+
+		Ok(Self {
+			name: String::from(name),
+			max_lines,
+			lines: Arc::new(Mutex::new(VecDeque::with_capacity(max_lines))),
+			done_sender,
+			done,
+			readers: Vec::new(),
+			stop_reader: unsafe { File::from_raw_fd(fds[0]) },
+			stop_writer: Some(unsafe { File::from_raw_fd(fds[1]) })
+		})
+	}
+
+	/// Reads the pipe until it is closed or the capture is stopped. Every line is logged
+	/// with the level and the name of the provider and the stream as fields.
+	pub fn capture<R: Read + AsRawFd + Send + 'static>(&mut self, mut pipe: R, stream: &'static str, level: Level) {
+		let name = self.name.clone();
+		let max_lines = self.max_lines;
+		let lines = self.lines.clone();
+		let done_sender = self.done_sender.clone();
+		let stop_fd = self.stop_reader.as_raw_fd();
+
+		self.readers.push(thread::spawn(move || {
+			let mut buffer = Vec::new();
+			let mut chunk = [0u8; 4096];
+
+			loop {
+				let mut poll_fds = [
+					libc::pollfd { fd: pipe.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+					libc::pollfd { fd: stop_fd, events: libc::POLLIN, revents: 0 }
+				];
+				if unsafe { libc::poll(poll_fds.as_mut_ptr(), poll_fds.len() as libc::nfds_t, -1) } < 0 {
+					if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted { continue; }
+					break;
+				}
+				// The stop is checked first. Otherwise a provider that keeps writing would
+				// keep the reader running.
+				if poll_fds[1].revents != 0 { break; }
+
+				let length = match pipe.read(&mut chunk) {
+					Ok(0) => break,
+					Ok(length) => length,
+					Err(ref error) if error.kind() == io::ErrorKind::Interrupted => continue,
+					Err(_) => break
+				};
+				buffer.extend_from_slice(&chunk[..length]);
+
+				while let Some(end) = buffer.iter().position(|v| *v == b'\n') {
+					let line: Vec<u8> = buffer.drain(..=end).collect();
+					record(&name, stream, level, max_lines, &lines, &line);
+				}
+			}
+
+			if !buffer.is_empty() { record(&name, stream, level, max_lines, &lines, &buffer); }
+			let _ = done_sender.send(());
+		}));
+	}
+
+	/// Stops all readers and waits until they terminated.
+	fn stop(&mut self) {
+		self.stop_writer.take();
+		for reader in self.readers.drain(..) {
+			let _ = reader.join();
+		}
+	}
+
+	/// Waits until all pipes are closed and returns the last lines. Processes started
+	/// by the provider may keep the pipes open. Therefore it waits at most for the timeout
+	/// and stops the readers afterwards.
+	pub fn finish(mut self, timeout: Duration) -> Vec<String> {
+		let deadline = Instant::now() + timeout;
+		for _ in 0..self.readers.len() {
+			if self.done.recv_timeout(deadline.saturating_duration_since(Instant::now())).is_err() { break; }
+		}
+		self.stop();
+
+		self.lines.lock().map(|v| v.iter().cloned().collect()).unwrap_or_default()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::io::Write;
+	use std::os::unix::net::UnixStream;
+
+	/// Verify that only the last lines of all pipes are kept.
+	#[test]
+	fn last_lines() {
+		let (mut writer, reader) = UnixStream::pair().unwrap();
+		writer.write_all(b"first\nsecond\r\nthird").unwrap();
+		drop(writer);
+
+		let mut output = ProviderOutput::new("test", 2).unwrap();
+		output.capture(reader, "stdout", Level::Info);
+
+		assert_eq!(output.finish(Duration::from_secs(5)), vec![ "second", "third" ]);
+	}
+
+	/// Verify that readers of pipes that stay open are stopped after the timeout.
+	#[test]
+	fn stop_readers() {
+		let (mut writer, reader) = UnixStream::pair().unwrap();
+		writer.write_all(b"open\n").unwrap();
+
+		let mut output = ProviderOutput::new("test", 2).unwrap();
+		output.capture(reader, "stdout", Level::Info);
+
+		assert_eq!(output.finish(Duration::from_millis(100)), vec![ "open" ]);
+	}
+}
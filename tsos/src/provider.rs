@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+use std::error;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::env;
+use std::fs;
+
+use crate::system::{UId, GId};
+
+/// Information about the secret that is provisioned by a secret provider. External
+/// providers get this information via environment variables (see env).
+#[derive(Clone)]
+pub struct ProviderContext {
+	name: String,
+	template: PathBuf,
+	config_file: PathBuf,
+	exec: PathBuf,
+	uid: UId,
+	gid: GId,
+	unit: Option<String>
+}
+
+/// Returns the name of the systemd unit TSoS was started by. systemd does not pass the
+/// unit name on. It is the last part of the cgroup path of the process.
+fn systemd_unit() -> Option<String> {
+	env::var_os("INVOCATION_ID")?;
+
+	let cgroup = fs::read_to_string("/proc/self/cgroup").ok()?;
+	let path = cgroup.lines().find_map(|v| v.strip_prefix("0::"))?;
+	let unit = path.rsplit('/').next()?;
+
+	if unit.ends_with(".service") { Some(String::from(unit)) } else { None }
+}
+
+impl ProviderContext {
+	pub(crate) fn new(config_file: &Path, exec: &Path, uid: UId, gid: GId) -> Self {
+		Self {
+			name: String::new(),
+			template: PathBuf::new(),
+			config_file: PathBuf::from(config_file),
+			exec: PathBuf::from(exec),
+			uid,
+			gid,
+			unit: systemd_unit()
+		}
+	}
+
+	/// Creates the context for provisioning a single template.
+	pub(crate) fn for_template(&self, name: &str, template: &Path) -> Self {
+		Self {
+			name: String::from(name),
+			template: PathBuf::from(template),
+			..self.clone()
+		}
+	}
+
+	/// The name of the secret provider within the configuration.
+	pub fn name(&self) -> &str {
+		&self.name
+	}
+
+	/// The template as it is named within the configuration.
+	pub fn template(&self) -> &Path {
+		&self.template
+	}
+
+	/// The configuration file of TSoS.
+	pub fn config_file(&self) -> &Path {
+		&self.config_file
+	}
+
+	/// The executable the secrets are provisioned for.
+	pub fn exec(&self) -> &Path {
+		&self.exec
+	}
+
+	/// The user id the executable is started as.
+	pub fn uid(&self) -> UId {
+		self.uid
+	}
+
+	/// The group id the executable is started as.
+	pub fn gid(&self) -> GId {
+		self.gid
+	}
+
+	/// The systemd unit TSoS was started by (e.g. `app@blue.service`).
+	pub fn unit(&self) -> Option<&str> {
+		self.unit.as_deref()
+	}
+
+	/// The instance name of a templated systemd unit (e.g. `blue` for `app@blue.service`).
+	pub fn instance(&self) -> Option<&str> {
+		let unit = self.unit.as_deref()?;
+		let (_, instance) = unit.strip_suffix(".service")?.split_once('@')?;
+
+		if instance.is_empty() { None } else { Some(instance) }
+	}
+
+	/// The environment variables passed to external providers.
+	pub fn env(&self) -> Vec<(&'static str, String)> {
+		let mut env = vec![
+			("TSOS_PROVIDER", self.name.clone()),
+			("TSOS_TEMPLATE", self.template.display().to_string()),
+			("TSOS_CONFIG", self.config_file.display().to_string()),
+			("TSOS_EXEC", self.exec.display().to_string()),
+			("TSOS_UID", self.uid.to_string()),
+			("TSOS_GID", self.gid.to_string()),
+			("TSOS_VERSION", String::from(env!("CARGO_PKG_VERSION")))
+		];
+		if let Some(unit) = self.unit() { env.push(("TSOS_UNIT", String::from(unit))); }
+		if let Some(instance) = self.instance() { env.push(("TSOS_INSTANCE", String::from(instance))); }
+
+		env
+	}
+}
+
+/// A secret provider running within the TSoS process. It reads the template and writes
+/// the file that is bound onto the template. Returning an error aborts the preparation
+/// like a failing external provider. out is locked memory that is wiped afterwards.
+/// Providers should write the secret into it directly instead of staging it in their
+/// own buffers.
+pub trait SecretProvider: Send + Sync {
+	fn render(&self, template: &mut dyn Read, out: &mut dyn Write, ctx: &ProviderContext) -> Result<(), Box<dyn error::Error>>;
+}
+
+/// In-process secret providers by name. They are looked up before the search path.
+#[derive(Default)]
+pub struct ProviderRegistry {
+	providers: HashMap<String, Box<dyn SecretProvider>>
+}
+
+impl ProviderRegistry {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Registers the provider. A provider already registered with this name is replaced.
+	pub fn register<P: SecretProvider + 'static>(&mut self, name: &str, provider: P) {
+		self.providers.insert(String::from(name), Box::new(provider));
+	}
+
+	pub fn get(&self, name: &str) -> Option<&dyn SecretProvider> {
+		self.providers.get(name).map(|v| v.as_ref())
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	/// Verify the environment passed to external providers.
+	#[test]
+	fn provider_env() {
+		let mut context = ProviderContext::new(Path::new("/etc/app.toml"), Path::new("/usr/bin/app"), 1000, 100).for_template("vault", Path::new("/etc/app.conf"));
+		context.unit = Some(String::from("app@blue.service"));
+
+		let env = context.env();
+		assert!(env.contains(&("TSOS_PROVIDER", String::from("vault"))));
+		assert!(env.contains(&("TSOS_TEMPLATE", String::from("/etc/app.conf"))));
+		assert!(env.contains(&("TSOS_CONFIG", String::from("/etc/app.toml"))));
+		assert!(env.contains(&("TSOS_EXEC", String::from("/usr/bin/app"))));
+		assert!(env.contains(&("TSOS_UID", String::from("1000"))));
+		assert!(env.contains(&("TSOS_GID", String::from("100"))));
+		assert!(env.contains(&("TSOS_UNIT", String::from("app@blue.service"))));
+		assert!(env.contains(&("TSOS_INSTANCE", String::from("blue"))));
+
+		context.unit = Some(String::from("app.service"));
+		assert_eq!(context.instance(), None);
+	}
+}
@@ -0,0 +1,794 @@
+use std::path::{Path, PathBuf};
+use std::error;
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+use std::env;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::fmt;
+use std::ffi::{OsStr, OsString};
+use std::sync::Arc;
+use std::time::Duration;
+use std::fs::{self, File, OpenOptions};
+use std::thread;
+
+use log::{debug, warn, Level};
+
+use crate::system::{self, TempDir, RamFs, DetachedRamFs, PinnedFile, CapabilityReport, Sandbox, Access, SecretBuffer, Undumpable, ReadOnce, UId, GId};
+use crate::audit::{Audit, Event};
+use crate::config::{Config, Id, SymlinkPolicy, Protocol, ProviderSettings, RetrySettings, SigkillPolicy};
+use crate::provider::{ProviderContext, ProviderRegistry, SecretProvider};
+use crate::error::Error;
+use crate::output::ProviderOutput;
+
+/// Search path passed to the secret providers if TSoS has none.
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Exit codes of a provider that are retried if none are configured. 75 is EX_TEMPFAIL
+/// of sysexits.h.
+const TRANSIENT_EXIT_CODES: &[i32] = &[ 75 ];
+
+/// Delays between the attempts of a provider in seconds if none are configured.
+const DEFAULT_RETRY_DELAY: f64 = 1.0;
+const DEFAULT_RETRY_MAX_DELAY: f64 = 30.0;
+
+/// Number of output lines of a failed provider that are added to the error message if
+/// output_lines is not configured.
+const DEFAULT_OUTPUT_LINES: usize = 10;
+
+/// Time the output of a provider is read after it terminated. Processes started by the
+/// provider may keep the pipes open.
+const OUTPUT_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Failures while provisioning the secrets. See Error for their codes.
+#[derive(Debug)]
+pub enum ProvisionError {
+	ProviderNotFound(String),
+	/// The provider, its exit code and its last output lines.
+	ProviderFailed(PathBuf, i32, Vec<String>),
+	/// The provider, its PID, the signal, whether a core was dumped and its last output lines.
+	ProviderTerminated(PathBuf, u32, i32, bool, Vec<String>),
+	/// The provider, its PID and its last output lines. The provider was killed by SIGKILL
+	/// and on_sigkill is set to oom or retry.
+	ProviderKilled(PathBuf, u32, Vec<String>),
+	ProviderNoFile(PathBuf),
+	TemplateNotFound(String, String),
+	InvalidSourceName(String),
+	ProviderHashMismatch(PathBuf, String, String),
+	ProviderError(String, Box<dyn error::Error>),
+	ProviderTimeout(PathBuf, u64),
+}
+
+/// Appends the last output lines of a provider to its error message.
+fn write_output(f: &mut fmt::Formatter<'_>, output: &[String]) -> fmt::Result {
+	if !output.is_empty() {
+		write!(f, " Last output:")?;
+		for line in output.iter() { write!(f, "\n    {}", line)?; }
+	}
+	Ok(())
+}
+
+impl fmt::Display for ProvisionError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::ProviderNotFound(sos) => write!(f, "Provider {} not found in search path.", sos),
+			Self::ProviderFailed(provider_file, result_code, output) => {
+				write!(f, "Provider {} failed to execute with result code {}.", provider_file.display(), result_code)?;
+				write_output(f, output)
+			},
+			Self::ProviderTerminated(provider_file, pid, signal, core_dumped, output) => {
+				write!(f, "Provider {} (PID {}) terminated by signal {} ({}){}.", provider_file.display(), pid, signal, system::signal_name(*signal), if *core_dumped { ", core dumped" } else { "" })?;
+				write_output(f, output)
+			},
+			Self::ProviderKilled(provider_file, pid, output) => {
+				write!(f, "Provider {} (PID {}) was killed by SIGKILL. It probably ran out of memory.", provider_file.display(), pid)?;
+				write_output(f, output)
+			},
+			Self::ProviderNoFile(provider_file) => write!(f, "{} is not a file.", provider_file.display()),
+			Self::TemplateNotFound(sos, source_file) => write!(f, "Template file {} for secret provider {} not found.", source_file, sos),
+			Self::InvalidSourceName(sos) => write!(f, "Invalid source name {}.", sos),
+			Self::ProviderHashMismatch(provider_file, expected, actual) => write!(f, "Provider {} does not match its SHA-256 hash. Expected {} but found {}.", provider_file.display(), expected, actual),
+			Self::ProviderError(sos, error) => write!(f, "Provider {} failed: {}", sos, error),
+			Self::ProviderTimeout(provider_file, timeout) => write!(f, "Provider {} did not finish within {} seconds.", provider_file.display(), timeout)
+		}
+	}
+}
+
+impl error::Error for ProvisionError {
+	fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+		match self {
+			Self::ProviderError(_, error) => Some(error.as_ref()),
+			_ => None
+		}
+	}
+}
+
+fn find_provider(search_path: &[PathBuf], provider_name: &OsStr) -> Option<PathBuf> {
+	for path in search_path {
+		if path.is_dir() {
+			let mut provider_path = PathBuf::from(path);
+			provider_path.push(provider_name);
+
+			debug!("Trying {} as secret provider...", provider_path.display());
+
+			//TODO: Check the file is only writable by root.
+
+			if provider_path.is_file() {
+				return Some(provider_path);
+			}
+		} else {
+			debug!("Search path {} not found or no directory.", path.display());
+		}
+	}
+
+	None
+}
+
+/// A secret provider found within the search path.
+struct Provider {
+	path: PathBuf,
+	/// If a hash is configured, the provider is copied into a sealed memfd and the copy
+	/// is verified and executed. That way exactly the verified content runs.
+	pinned: Option<PinnedFile>,
+	sha256: String
+}
+
+impl Provider {
+	/// Verifies the provider against the expected SHA-256 hash if one is configured.
+	/// The hash is calculated anyway for the audit trail.
+	fn new(path: PathBuf, expected_sha256: Option<&String>) -> Result<Self, Box<dyn error::Error>> {
+		if let Some(expected_sha256) = expected_sha256 {
+			let pinned = PinnedFile::open(&path, true)?.sealed_copy()?;
+			let sha256 = system::sha256_hex(&mut File::open(&pinned)?)?;
+
+			if !sha256.eq_ignore_ascii_case(expected_sha256) {
+				return Err(Box::new(ProvisionError::ProviderHashMismatch(path, expected_sha256.clone(), sha256)));
+			}
+
+			debug!("Secret provider {} matches its SHA-256 hash.", path.display());
+
+			Ok(Self { path, pinned: Some(pinned), sha256 })
+		} else {
+			let sha256 = system::sha256_hex(&mut File::open(&path)?)?;
+			Ok(Self { path, pinned: None, sha256 })
+		}
+	}
+
+	/// The path used to execute the provider.
+	fn exec_path(&self) -> &Path {
+		match self.pinned {
+			Some(ref pinned) => pinned.as_ref(),
+			None => &self.path
+		}
+	}
+
+	fn command(&self) -> Command {
+		let mut command = Command::new(self.exec_path());
+		if let Some(ref pinned) = self.pinned { pinned.pass_to(&mut command); }
+
+		command
+	}
+}
+
+/// A secret provider is either an executable or runs within the TSoS process.
+enum ProviderKind<'a> {
+	External(Provider),
+	InProcess(&'a dyn SecretProvider)
+}
+
+/// In-memory storage for the files created by the secret providers.
+enum SecretStore {
+	/// A ramfs mounted onto a temporary directory. The ramfs is unmounted before
+	/// the temporary directory is removed.
+	Mounted { _mount: RamFs, temp: TempDir },
+	/// A ramfs that is never attached to a path.
+	Detached(DetachedRamFs)
+}
+
+impl SecretStore {
+	fn new(config: &Config) -> Result<Self, Box<dyn error::Error>> {
+		if config.local.detached_mount.unwrap_or(false) {
+			Ok(Self::Detached(DetachedRamFs::new("tsos")?))
+		} else {
+			// Create temporary directory and mount a ramfs onto it
+			let temp = TempDir::new("tsos")?;
+			let mut temp_mount = RamFs::new("tsos", temp.as_ref())?;
+			if config.local.read_once.as_ref().map(|v| !v.is_empty()).unwrap_or(false) { temp_mount.unmount_lazily(); }
+
+			Ok(Self::Mounted { _mount: temp_mount, temp })
+		}
+	}
+
+	fn create_file(&mut self, prefix: &str) -> Result<PathBuf, system::Error> {
+		match self {
+			Self::Mounted { temp, .. } => temp.create_file(prefix),
+			Self::Detached(ramfs) => ramfs.create_file(prefix)
+		}
+	}
+
+	/// Makes sure the passed file is accessible for the command.
+	fn pass_file(&self, command: &mut Command, file: &Path) {
+		if let Self::Detached(ramfs) = self { ramfs.pass_file(command, file); }
+	}
+
+	/// Removes the content of a file created by create_file.
+	fn discard_file(&self, file: &Path) -> io::Result<()> {
+		match self {
+			Self::Mounted { .. } => fs::remove_file(file),
+			Self::Detached(_) => OpenOptions::new().write(true).open(file)?.set_len(0)
+		}
+	}
+
+	fn bind(&self, file: &Path, target: &Path) -> Result<(), system::Error> {
+		match self {
+			Self::Mounted { .. } => system::bind(file, target),
+			Self::Detached(ramfs) => ramfs.bind(file, target)
+		}
+	}
+}
+
+/// Creates the sandbox for a secret provider if it is enabled within the configuration.
+/// The provider may read the template and its own executable and may write the target file.
+fn provider_sandbox(config: &Config, provider_file: &Path, template: &Path, target: &Path) -> Result<Option<Sandbox>, system::Error> {
+	let landlock = config.local.provider_landlock.unwrap_or(false);
+	let seccomp = config.local.provider_seccomp.unwrap_or(false);
+
+	if !landlock && !seccomp {
+		return Ok(None);
+	}
+
+	let mut sandbox = Sandbox::new();
+
+	if landlock {
+		sandbox.restrict_paths()?;
+		sandbox.allow(provider_file, Access::Read)?;
+		sandbox.allow(template, Access::Read)?;
+		sandbox.allow(target, Access::Write)?;
+
+		for path in config.local.provider_read.iter().flatten() { sandbox.allow(path, Access::Read)?; }
+		for path in config.local.provider_write.iter().flatten() { sandbox.allow(path, Access::Write)?; }
+	}
+
+	if seccomp {
+		sandbox.deny_mount_and_ptrace()?;
+	}
+
+	Ok(Some(sandbox))
+}
+
+/// Passes the context to the provider. The environment of TSoS is not passed on except
+/// for PATH and the variables listed within provider_env_allow.
+fn prepare_provider_environment(command: &mut Command, config: &Config, settings: Option<&ProviderSettings>, context: &ProviderContext) {
+	command.env_clear();
+	command.env("PATH", env::var_os("PATH").unwrap_or_else(|| OsString::from(DEFAULT_PATH)));
+
+	for key in config.local.provider_env_allow.iter().flatten() {
+		if let Some(value) = env::var_os(key) { command.env(key, value); }
+	}
+	if let Some(provider_env) = settings.and_then(|v| v.env.as_ref()) {
+		for (key, value) in provider_env.iter() { command.env(key, value); }
+	}
+
+	for (key, value) in context.env() { command.env(key, value); }
+}
+
+/// Executes the secret provider. By default it will use the input file ($1) and update
+/// the output file ($2). The configured arguments are passed after these.
+fn run_provider(config: &Config, store: &SecretStore, provider: &Provider, template: &PinnedFile, target: &Path, context: &ProviderContext) -> Result<(), Box<dyn error::Error>> {
+	debug!("Executing secret provider...");
+
+	let settings = config.local.providers.as_ref().and_then(|v| v.get(context.name()));
+
+	let mut command = provider.command();
+	match settings.and_then(|v| v.protocol).unwrap_or(Protocol::Files) {
+		Protocol::Files => {
+			command.arg(template.as_ref()).arg(target);
+			template.pass_to(&mut command);
+			store.pass_file(&mut command, target);
+			// Providers may echo what they render. Therefore stdout is only logged if
+			// this is enabled explicitly.
+			command.stdout(if settings.and_then(|v| v.log_stdout).unwrap_or(false) { Stdio::piped() } else { Stdio::null() });
+		},
+		Protocol::Stdio => {
+			command.stdin(File::open(template)?);
+			command.stdout(OpenOptions::new().write(true).truncate(true).open(target)?);
+		}
+	}
+	command.stderr(Stdio::piped());
+	if let Some(args) = settings.and_then(|v| v.args.as_ref()) { command.args(args); }
+	prepare_provider_environment(&mut command, config, settings, context);
+	if let Some(sandbox) = provider_sandbox(config, provider.exec_path(), template.as_ref(), target)? {
+		unsafe { command.pre_exec(move || sandbox.apply()); }
+	}
+
+	// With a timeout the provider runs within its own process group. That way the
+	// processes it started are terminated with it.
+	let timeout = settings.and_then(|v| v.timeout);
+	if timeout.is_some() { command.process_group(0); }
+
+	// The output of the provider is logged with its name. stdout is only captured if it
+	// does not carry the secret and log_stdout is set.
+	let mut output = ProviderOutput::new(context.name(), settings.and_then(|v| v.output_lines).unwrap_or(DEFAULT_OUTPUT_LINES))?;
+	let mut child = command.spawn()?;
+	let pid = child.id();
+	debug!(tsos_provider = context.name(), tsos_provider_pid = pid; "Provider {} started with PID {}.", context.name(), pid);
+	if let Some(stdout) = child.stdout.take() { output.capture(stdout, "stdout", Level::Info); }
+	if let Some(stderr) = child.stderr.take() { output.capture(stderr, "stderr", Level::Warn); }
+
+	let exit_code = match timeout {
+		Some(timeout) => match system::wait_timeout(&mut child, Duration::from_secs(timeout))? {
+			Some(exit_code) => exit_code,
+			None => {
+				unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGKILL) };
+				let _ = child.wait();
+				output.finish(OUTPUT_DRAIN_TIMEOUT);
+				return Err(Box::new(ProvisionError::ProviderTimeout(provider.path.clone(), timeout)));
+			}
+		},
+		None => child.wait()?
+	};
+	let output = output.finish(OUTPUT_DRAIN_TIMEOUT);
+	if !exit_code.success() {
+		if let Some(code) = exit_code.code() {
+			return Err(Box::new(ProvisionError::ProviderFailed(provider.path.clone(), code, output)));
+		}
+
+		let signal = exit_code.signal().unwrap_or(0);
+		let on_sigkill = settings.and_then(|v| v.on_sigkill).unwrap_or(SigkillPolicy::Fail);
+		if signal == libc::SIGKILL && on_sigkill != SigkillPolicy::Fail {
+			return Err(Box::new(ProvisionError::ProviderKilled(provider.path.clone(), pid, output)));
+		}
+		return Err(Box::new(ProvisionError::ProviderTerminated(provider.path.clone(), pid, signal, exit_code.core_dumped(), output)));
+	}
+
+	Ok(())
+}
+
+/// Returns true if the failure of a provider is transient and the provider may be
+/// run again.
+fn is_transient(retry: &RetrySettings, on_sigkill: SigkillPolicy, error: &(dyn error::Error + 'static)) -> bool {
+	match error.downcast_ref::<ProvisionError>() {
+		Some(ProvisionError::ProviderFailed(_, code, _)) => retry.exit_codes.as_deref().unwrap_or(TRANSIENT_EXIT_CODES).contains(code),
+		Some(ProvisionError::ProviderKilled(_, _, _)) => on_sigkill == SigkillPolicy::Retry,
+		_ => false
+	}
+}
+
+/// The delay before the next attempt. It doubles with every attempt up to max_delay.
+/// Half of the delay is random (random is between 0 and 1). That way several services
+/// waiting for the same provider do not retry at once.
+fn backoff(retry: &RetrySettings, attempt: u32, random: f64) -> Duration {
+	let delay = retry.delay.unwrap_or(DEFAULT_RETRY_DELAY) * 2f64.powi(attempt.saturating_sub(1).min(62) as i32);
+	let delay = delay.min(retry.max_delay.unwrap_or(DEFAULT_RETRY_MAX_DELAY)).max(0.0);
+
+	Duration::from_secs_f64(delay / 2.0 + delay / 2.0 * random)
+}
+
+/// Runs the provider until it succeeds, fails permanently or all attempts are used up.
+/// Every attempt writes into a fresh target file. Returns the target file of the last
+/// attempt.
+fn run_provider_with_retries(config: &Config, store: &mut SecretStore, provider: &Provider, template: &PinnedFile, context: &ProviderContext, event: &mut Event) -> Result<PathBuf, Box<dyn error::Error>> {
+	let settings = config.local.providers.as_ref().and_then(|v| v.get(context.name()));
+	let retry = settings.and_then(|v| v.retry.as_ref());
+	let on_sigkill = settings.and_then(|v| v.on_sigkill).unwrap_or(SigkillPolicy::Fail);
+	let attempts = retry.and_then(|v| v.attempts).unwrap_or(1).max(1);
+
+	let mut attempt = 1;
+	loop {
+		let target = store.create_file("tsos-final")?;
+		match (run_provider(config, store, provider, template, &target, context), retry) {
+			(Err(error), Some(retry)) if attempt < attempts && is_transient(retry, on_sigkill, error.as_ref()) => {
+				// The failed attempt may have written parts of the secret.
+				store.discard_file(&target)?;
+
+				let random = system::random_u64().map(|v| (v >> 11) as f64 / (1u64 << 53) as f64).unwrap_or(0.5);
+				let delay = backoff(retry, attempt, random);
+				warn!("Attempt {} of {} failed: {} Retrying in {:.1} seconds.", attempt, attempts, error, delay.as_secs_f64());
+				thread::sleep(delay);
+
+				attempt += 1;
+			},
+			(result, _) => {
+				if attempt > 1 { event.field("tsos_provider_attempts", attempt); }
+				return result.map(|_| target);
+			}
+		}
+	}
+}
+
+/// Runs the secret provider for a single template and binds the result onto the template.
+fn provision(config: &Config, store: &mut SecretStore, read_once: &mut ReadOnce, provider: &ProviderKind, context: &ProviderContext, template: &str, event: &mut Event) -> Result<(), Box<dyn error::Error>> {
+	let follow_symlinks = config.local.template_symlinks.unwrap_or(SymlinkPolicy::Follow) == SymlinkPolicy::Follow;
+
+	// Open the template only once. All following operations use the pinned file.
+	// That way the template can not be replaced while we are working with it.
+	let pinned_template = match PinnedFile::open(Path::new(template), follow_symlinks) {
+		Ok(pinned_template) => pinned_template,
+		Err(system::Error::OsError(ref error)) if error.kind() == io::ErrorKind::NotFound => {
+			return Err(Box::new(ProvisionError::TemplateNotFound(context.name().to_owned(), String::from(template))));
+		},
+		Err(system::Error::NotAFile(_)) => {
+			return Err(Box::new(ProvisionError::TemplateNotFound(context.name().to_owned(), String::from(template))));
+		},
+		Err(error) => return Err(Box::new(error))
+	};
+	event.field("tsos_template_inode", pinned_template.inode());
+
+	let read_once_name = config.local.read_once.iter().flatten().find(|v| *v == template);
+	let template = pinned_template.as_ref();
+
+	let target = match provider {
+		ProviderKind::External(provider) => run_provider_with_retries(config, store, provider, &pinned_template, context, event)?,
+		ProviderKind::InProcess(provider) => {
+			debug!("Rendering secret with in-process provider...");
+
+			// The provider renders into locked memory. Nothing is written to the target
+			// if it fails.
+			let mut secret = SecretBuffer::with_capacity(0)?;
+			if let Err(error) = provider.render(&mut File::open(template)?, &mut secret, context) {
+				return Err(Box::new(ProvisionError::ProviderError(context.name().to_owned(), error)));
+			}
+
+			let target = store.create_file("tsos-final")?;
+			OpenOptions::new().write(true).truncate(true).open(&target)?.write_all(&secret)?;
+			target
+		}
+	};
+
+	debug!("Copying permissions...");
+
+	system::copy_perms_and_owners(template, &target)?;
+
+	store.bind(&target, template)?;
+
+	if let Some(template_name) = read_once_name {
+		read_once.add(&target, context.name(), template_name)?;
+	}
+
+	Ok(())
+}
+
+/// Provisions all secrets within a new mount namespace. The returned store must be kept
+/// until the provisioned files are no longer accessed via the store. The read once
+/// files are returned if there are any.
+fn prepare(config: &Config, audit: &Audit, providers: &ProviderRegistry, context: &ProviderContext) -> Result<(SecretStore, Option<ReadOnce>), Box<dyn error::Error>> {
+	// Secret providers run as the same user. Make sure they can not attach to TSoS.
+	let _undumpable = Undumpable::new()?;
+
+	system::unshare_mount_ns()?;
+
+	let mut store = SecretStore::new(config)?;
+	let mut read_once = ReadOnce::new()?;
+
+	for (sos, templates) in config.local.secrets.iter() {
+		debug!("Processing secret provider {}...", sos);
+
+		let mut event = audit.event("provision");
+		event.field("tsos_provider", sos);
+
+		let expected_sha256 = config.local.provider_sha256.as_ref().and_then(|v| v.get(sos));
+
+		// Several providers may share one executable.
+		let name = sos;
+		let executable = config.local.providers.as_ref().and_then(|v| v.get(name)).and_then(|v| v.executable.as_ref()).unwrap_or(name);
+
+		// Make sure the file name can not be used for path traversal attacks
+		let sos = match Path::new(executable).file_name() {
+			Some(sos) if !executable.contains('/') => sos,
+			_ => return Err(audit_failure(event, Box::new(ProvisionError::InvalidSourceName(executable.clone()))))
+		};
+
+		// In-process providers take precedence over the search path. A configured hash
+		// always refers to an executable.
+		let provider = if let (Some(provider), None) = (providers.get(name), expected_sha256) {
+			debug!("Using in-process secret provider {}.", name);
+			ProviderKind::InProcess(provider)
+		} else {
+			// Search for the secret provider
+			// If a local search path is configured it takes precedence over the global search path.
+			let mut provider_search_result = if let Some(ref search_path) = config.local.search_path { find_provider(search_path, sos) } else { None };
+			if provider_search_result.is_none() {
+				provider_search_result = find_provider(&config.global.search_path, sos);
+			}
+
+			let provider_file = match provider_search_result {
+				Some(provider_file) => provider_file,
+				None => return Err(audit_failure(event, Box::new(ProvisionError::ProviderNotFound(sos.to_string_lossy().into_owned()))))
+			};
+
+			debug!("Found secret provider {} for secret {}.", provider_file.display(), sos.to_string_lossy());
+			event.field("tsos_provider_path", provider_file.display());
+
+			let provider = match Provider::new(provider_file, expected_sha256) {
+				Ok(provider) => provider,
+				Err(error) => return Err(audit_failure(event, error))
+			};
+			event.field("tsos_provider_sha256", &provider.sha256);
+
+			ProviderKind::External(provider)
+		};
+
+		for template in templates.iter() {
+			let mut event = event.clone();
+			event.field("tsos_template", template);
+
+			let context = context.for_template(name, Path::new(template));
+			match provision(config, &mut store, &mut read_once, &provider, &context, template, &mut event) {
+				Ok(()) => event.success(format_args!("Provisioned {} with secret provider {}.", template, sos.to_string_lossy())).map_err(Error::Audit)?,
+				Err(error) => return Err(audit_failure(event, error))
+			}
+		}
+	}
+
+	Ok((store, if read_once.is_empty() { None } else { Some(read_once) }))
+}
+
+/// Records the failure within the audit trail and returns the error. Failing to record
+/// the event is only logged. The original error is more important.
+fn audit_failure(event: Event, error: Box<dyn error::Error>) -> Box<dyn error::Error> {
+	if let Err(audit_error) = event.failure(format_args!("Provisioning secrets failed: {}", error), &error) {
+		warn!("Writing audit event failed: {}", audit_error);
+	}
+
+	error
+}
+
+/// Resolves the user and group the executable is started as. None means the id is
+/// not changed.
+fn resolve_ids(config: &Config) -> Result<(Option<UId>, Option<GId>), system::Error> {
+	let uid_gid = match config.local.uid {
+		Some(Id::Nummeric(ref uid)) => Some(system::resolve_uid(*uid)?),
+		Some(Id::Text(ref user_name)) => Some(system::resolve_user(user_name)?),
+		None => None
+	};
+	let gid = match config.local.gid {
+		Some(Id::Nummeric(ref gid)) => Some(*gid),
+		Some(Id::Text(ref group_name)) => Some(system::resolve_group(group_name)?),
+		None => None
+	};
+
+	// A configured gid takes precedence over the primary group of the user.
+	Ok(match uid_gid {
+		Some((uid, ugid)) => (Some(uid), Some(gid.unwrap_or(ugid))),
+		None => (None, gid)
+	})
+}
+
+/// Failures of setting the capabilities are sent to the returned report.
+fn prepare_privileges(command: &mut Command, config: &Config, uid: Option<UId>, gid: Option<GId>) -> Result<Option<Arc<CapabilityReport>>, Box<dyn error::Error>> {
+	// An explicit list of supplementary groups takes precedence. Otherwise the
+	// supplementary groups of the configured user are used (like initgroups does).
+	let groups = if let Some(ref groups) = config.local.groups {
+		let mut gids = Vec::with_capacity(groups.len());
+		for group in groups.iter() {
+			gids.push(match group {
+				Id::Nummeric(gid) => *gid,
+				Id::Text(group_name) => system::resolve_group(group_name)?
+			});
+		}
+		Some(gids)
+	} else if let (Some(uid), Some(gid)) = (uid, gid) {
+		if config.local.init_groups.unwrap_or(true) { Some(system::user_groups(uid, gid)?) } else { Some(Vec::new()) }
+	} else {
+		None
+	};
+
+	let capabilities = match config.local.ambient_capabilities {
+		Some(ref names) => Some(system::resolve_capabilities(names)?),
+		None => None
+	};
+	let lock_securebits = config.local.lock_securebits.unwrap_or(false);
+	let no_new_privs = config.local.no_new_privs.unwrap_or(false);
+
+	// spawn and exec only return the errno of a failed pre_exec hook. The report
+	// tells which capability could not be set.
+	let report = if capabilities.is_some() || lock_securebits { Some(Arc::new(CapabilityReport::new()?)) } else { None };
+	let child_report = report.clone();
+
+	// Command::uid drops all supplementary groups and runs pre_exec hooks after the
+	// user was switched. Therefore we switch the ids ourselves.
+	unsafe {
+		command.pre_exec(move || {
+			// The securebits must be set before the user id is switched. Otherwise we
+			// would lose the capabilities necessary to do so.
+			let result = system::prepare_securebits(capabilities.is_some(), lock_securebits);
+			if let (Err(failure), Some(report)) = (result, child_report.as_ref()) { report.send(failure); }
+			result?;
+
+			system::switch_ids(uid, gid, groups.as_deref())?;
+
+			if let Some(ref capabilities) = capabilities {
+				let result = system::set_ambient_capabilities(capabilities);
+				if let (Err(failure), Some(report)) = (result, child_report.as_ref()) { report.send(failure); }
+				result?;
+			}
+			if no_new_privs { system::set_no_new_privs()?; }
+
+			Ok(())
+		});
+	}
+
+	Ok(report)
+}
+
+/// Sets up the arguments, environment and working directory of the command according
+/// to the configuration. Fixed arguments from the configuration are added before the
+/// arguments passed on the command line.
+fn prepare_environment(command: &mut Command, config: &Config) {
+	if let Some(ref args) = config.local.args { command.args(args); }
+	if let Some(ref chdir) = config.local.chdir { command.current_dir(chdir); }
+
+	// If clear_env is set, nothing is passed through. An allow list passes only the
+	// listed variables. Both at once are rejected by the configuration. Otherwise the
+	// whole environment of TSoS is passed on.
+	if config.local.clear_env.unwrap_or(false) {
+		debug!("Clearing environment...");
+		command.env_clear();
+	} else if let Some(ref env_allow) = config.local.env_allow {
+		debug!("Passing only allowed environment variables...");
+		command.env_clear();
+		for key in env_allow {
+			if let Some(value) = env::var_os(key) { command.env(key, value); }
+		}
+	}
+
+	if let Some(ref env_deny) = config.local.env_deny {
+		for key in env_deny { command.env_remove(key); }
+	}
+
+	if let Some(ref set_env) = config.local.set_env {
+		for (key, value) in set_env.iter() { command.env(key, value); }
+	}
+}
+
+
+/// Keeps the storage of the provisioned secrets alive. Dropping the guard unmounts the
+/// ramfs and removes the temporary directory. The secrets bound onto the templates stay
+/// available within the mount namespace.
+pub struct Prepared {
+	_store: SecretStore
+}
+
+/// Prepares a process for execution with the secrets provisioned by TSoS.
+///
+/// `prepare` moves the calling thread into a new mount namespace and binds the secrets
+/// onto the templates. `command` returns the command that executes the configured
+/// executable with the configured privileges and environment. Processes spawned by
+/// the calling thread inherit the mount namespace.
+pub struct Session {
+	config: Config,
+	uid: Option<UId>,
+	gid: Option<GId>,
+	audit: Option<Audit>,
+	providers: ProviderRegistry,
+	report: Option<Arc<CapabilityReport>>,
+	read_once: Option<ReadOnce>
+}
+
+impl Drop for Session {
+	fn drop(&mut self) {
+		if self.read_once.is_some() {
+			warn!("The supervisor for read once files was never started. Their content was not removed.");
+		}
+	}
+}
+
+impl Session {
+	/// Creates the session and resolves the user and group the executable is started as.
+	pub fn from_config(config: Config) -> Result<Self, Error> {
+		let (uid, gid) = resolve_ids(&config)?;
+
+		Ok(Self {
+			config,
+			uid,
+			gid,
+			audit: None,
+			providers: ProviderRegistry::new(),
+			report: None,
+			read_once: None
+		})
+	}
+
+	pub fn config(&self) -> &Config {
+		&self.config
+	}
+
+	/// Registers an in-process secret provider. It is used instead of an executable with
+	/// the same name within the search path.
+	pub fn register_provider<P: SecretProvider + 'static>(&mut self, name: &str, provider: P) {
+		self.providers.register(name, provider);
+	}
+
+	/// The audit trail of the session. It is available after prepare was called.
+	pub fn audit(&self) -> Option<&Audit> {
+		self.audit.as_ref()
+	}
+
+	/// Runs the secret providers and binds the secrets onto the templates. The guard
+	/// must be dropped before the process is replaced by exec, because no destructors
+	/// run afterwards.
+	pub fn prepare(&mut self) -> Result<Prepared, Error> {
+		let uid = self.uid.unwrap_or_else(|| unsafe { libc::getuid() });
+		let gid = self.gid.unwrap_or_else(|| unsafe { libc::getgid() });
+		let audit = Audit::new(&self.config.file, &self.config.local.exec, uid, gid, self.config.local.audit_file.as_deref()).map_err(Error::Audit)?;
+		let context = ProviderContext::new(&self.config.file, &self.config.local.exec, uid, gid);
+
+		let (store, read_once) = prepare(&self.config, &audit, &self.providers, &context)?;
+		self.audit = Some(audit);
+		self.read_once = read_once;
+
+		Ok(Prepared { _store: store })
+	}
+
+	/// Starts the supervisor removing the content of the read once files after the
+	/// service has read them. It terminates with the service process. Pass the PID of
+	/// the spawned service, or the PID of this process right before it is replaced by
+	/// exec. Without it the read once files are never cleared. Does nothing if no read
+	/// once files were provisioned.
+	pub fn supervise_read_once(&mut self, service: u32) -> Result<(), Error> {
+		match self.read_once.take() {
+			Some(read_once) => Ok(read_once.supervise(service as libc::pid_t)?),
+			None => Ok(())
+		}
+	}
+
+	/// Creates the command executing the configured executable. The configured arguments
+	/// are already added. Further arguments can be appended.
+	pub fn command(&mut self) -> Result<Command, Error> {
+		let mut command = Command::new(&self.config.local.exec);
+		self.report = prepare_privileges(&mut command, &self.config, self.uid, self.gid)?;
+		prepare_environment(&mut command, &self.config);
+
+		Ok(command)
+	}
+
+	/// If spawning or executing the command failed, this returns the reason if setting
+	/// the capabilities failed.
+	pub fn capability_failure(&self) -> Option<system::Error> {
+		self.report.as_ref().and_then(|v| v.receive())
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	/// Verify that only the configured exit codes and SIGKILL with on_sigkill = "retry" are retried.
+	#[test]
+	fn transient_failures() {
+		let mut retry = RetrySettings { attempts: Some(3), delay: None, max_delay: None, exit_codes: None };
+		let failed = |code| ProvisionError::ProviderFailed(PathBuf::from("/bin/false"), code, Vec::new());
+		let killed = ProvisionError::ProviderKilled(PathBuf::from("/bin/false"), 1, Vec::new());
+
+		assert!(is_transient(&retry, SigkillPolicy::Fail, &failed(75)));
+		assert!(!is_transient(&retry, SigkillPolicy::Fail, &failed(1)));
+		assert!(!is_transient(&retry, SigkillPolicy::Retry, &ProvisionError::ProviderTerminated(PathBuf::from("/bin/false"), 1, libc::SIGTERM, false, Vec::new())));
+		assert!(!is_transient(&retry, SigkillPolicy::Oom, &killed));
+		assert!(is_transient(&retry, SigkillPolicy::Retry, &killed));
+
+		retry.exit_codes = Some(vec![ 1 ]);
+		assert!(is_transient(&retry, SigkillPolicy::Fail, &failed(1)));
+		assert!(!is_transient(&retry, SigkillPolicy::Fail, &failed(75)));
+	}
+
+	/// Verify that the signal, its name and a core dump are part of the message.
+	#[test]
+	fn terminated_message() {
+		let error = ProvisionError::ProviderTerminated(PathBuf::from("/bin/provider"), 42, libc::SIGSEGV, true, Vec::new());
+		assert_eq!(error.to_string(), "Provider /bin/provider (PID 42) terminated by signal 11 (SIGSEGV), core dumped.");
+
+		let error = ProvisionError::ProviderTerminated(PathBuf::from("/bin/provider"), 42, libc::SIGTERM, false, Vec::new());
+		assert_eq!(error.to_string(), "Provider /bin/provider (PID 42) terminated by signal 15 (SIGTERM).");
+	}
+
+	/// Verify that the delay doubles up to the maximum and keeps half of it fixed.
+	#[test]
+	fn backoff_delays() {
+		let retry = RetrySettings { attempts: None, delay: Some(1.0), max_delay: Some(5.0), exit_codes: None };
+
+		assert_eq!(backoff(&retry, 1, 0.0), Duration::from_millis(500));
+		assert_eq!(backoff(&retry, 1, 1.0), Duration::from_secs(1));
+		assert_eq!(backoff(&retry, 3, 1.0), Duration::from_secs(4));
+		assert_eq!(backoff(&retry, 4, 0.0), Duration::from_millis(2500));
+		assert_eq!(backoff(&retry, 100, 1.0), Duration::from_secs(5));
+	}
+}
@@ -0,0 +1,337 @@
+use log::debug;
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+
+use super::Error;
+
+pub type Capability = u32;
+
+/// Capability names from linux/capability.h. The index within this list is
+/// the number of the capability.
+const CAPABILITY_NAMES: &[&str] = &[
+	"CAP_CHOWN",
+	"CAP_DAC_OVERRIDE",
+	"CAP_DAC_READ_SEARCH",
+	"CAP_FOWNER",
+	"CAP_FSETID",
+	"CAP_KILL",
+	"CAP_SETGID",
+	"CAP_SETUID",
+	"CAP_SETPCAP",
+	"CAP_LINUX_IMMUTABLE",
+	"CAP_NET_BIND_SERVICE",
+	"CAP_NET_BROADCAST",
+	"CAP_NET_ADMIN",
+	"CAP_NET_RAW",
+	"CAP_IPC_LOCK",
+	"CAP_IPC_OWNER",
+	"CAP_SYS_MODULE",
+	"CAP_SYS_RAWIO",
+	"CAP_SYS_CHROOT",
+	"CAP_SYS_PTRACE",
+	"CAP_SYS_PACCT",
+	"CAP_SYS_ADMIN",
+	"CAP_SYS_BOOT",
+	"CAP_SYS_NICE",
+	"CAP_SYS_RESOURCE",
+	"CAP_SYS_TIME",
+	"CAP_SYS_TTY_CONFIG",
+	"CAP_MKNOD",
+	"CAP_LEASE",
+	"CAP_AUDIT_WRITE",
+	"CAP_AUDIT_CONTROL",
+	"CAP_SETFCAP",
+	"CAP_MAC_OVERRIDE",
+	"CAP_MAC_ADMIN",
+	"CAP_SYSLOG",
+	"CAP_WAKE_ALARM",
+	"CAP_BLOCK_SUSPEND",
+	"CAP_AUDIT_READ",
+	"CAP_PERFMON",
+	"CAP_BPF",
+	"CAP_CHECKPOINT_RESTORE"
+];
+
+// Bits from linux/securebits.h
+const SECBIT_NOROOT: libc::c_ulong = 1 << 0;
+const SECBIT_NOROOT_LOCKED: libc::c_ulong = 1 << 1;
+const SECBIT_NO_SETUID_FIXUP_LOCKED: libc::c_ulong = 1 << 3;
+const SECBIT_KEEP_CAPS: libc::c_ulong = 1 << 4;
+const SECBIT_KEEP_CAPS_LOCKED: libc::c_ulong = 1 << 5;
+
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+#[repr(C)]
+struct CapUserHeader {
+	version: u32,
+	pid: libc::c_int
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct CapUserData {
+	effective: u32,
+	permitted: u32,
+	inheritable: u32
+}
+
+/// Step of setting the capabilities between fork and exec.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CapabilityStep {
+	Securebits,
+	KeepCaps,
+	Capset,
+	Ambient(Capability)
+}
+
+impl CapabilityStep {
+	/// Name of the capability or the operation that failed.
+	fn name(&self) -> String {
+		match self {
+			Self::Securebits => String::from("securebits"),
+			Self::KeepCaps => String::from("keep_caps"),
+			Self::Capset => String::from("capset"),
+			Self::Ambient(capability) => CAPABILITY_NAMES.get(*capability as usize).map(|v| String::from(*v)).unwrap_or_else(|| capability.to_string())
+		}
+	}
+
+	fn encode(&self) -> [u32; 2] {
+		match self {
+			Self::Securebits => [0, 0],
+			Self::KeepCaps => [1, 0],
+			Self::Capset => [2, 0],
+			Self::Ambient(capability) => [3, *capability]
+		}
+	}
+
+	fn decode(value: [u32; 2]) -> Option<Self> {
+		match value {
+			[0, _] => Some(Self::Securebits),
+			[1, _] => Some(Self::KeepCaps),
+			[2, _] => Some(Self::Capset),
+			[3, capability] => Some(Self::Ambient(capability)),
+			_ => None
+		}
+	}
+}
+
+/// A failed step of setting the capabilities and the errno of the failure.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CapabilityFailure {
+	pub step: CapabilityStep,
+	pub errno: i32
+}
+
+impl CapabilityFailure {
+	fn last_os_error(step: CapabilityStep) -> Self {
+		Self { step, errno: io::Error::last_os_error().raw_os_error().unwrap_or(0) }
+	}
+}
+
+impl From<CapabilityFailure> for io::Error {
+	fn from(failure: CapabilityFailure) -> Self {
+		io::Error::from_raw_os_error(failure.errno)
+	}
+}
+
+impl From<CapabilityFailure> for Error {
+	fn from(failure: CapabilityFailure) -> Self {
+		Error::CapabilityFailed(failure.step.name(), failure.errno)
+	}
+}
+
+/// Passes a failure of setting the capabilities between fork and exec back to the
+/// parent. spawn and exec only return the errno.
+pub struct CapabilityReport {
+	read_fd: RawFd,
+	write_fd: RawFd
+}
+
+impl CapabilityReport {
+	pub fn new() -> io::Result<Self> {
+		let mut fds: [RawFd; 2] = [-1; 2];
+		if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) } != 0 {
+			return Err(io::Error::last_os_error());
+		}
+
+		Ok(Self { read_fd: fds[0], write_fd: fds[1] })
+	}
+
+	/// Sends the failure. This function is meant to be called between fork and exec.
+	/// Therefore it does not allocate any memory.
+	pub fn send(&self, failure: CapabilityFailure) {
+		let step = failure.step.encode();
+		let message: [u32; 3] = [ step[0], step[1], failure.errno as u32 ];
+		unsafe { libc::write(self.write_fd, message.as_ptr() as *const libc::c_void, mem::size_of_val(&message)) };
+	}
+
+	/// Receives a failure sent by send. Returns None if nothing was sent.
+	pub fn receive(&self) -> Option<Error> {
+		let mut message = [0u32; 3];
+		let len = unsafe { libc::read(self.read_fd, message.as_mut_ptr() as *mut libc::c_void, mem::size_of_val(&message)) };
+		if len != mem::size_of_val(&message) as isize {
+			return None;
+		}
+
+		CapabilityStep::decode([ message[0], message[1] ]).map(|step| Error::from(CapabilityFailure { step, errno: message[2] as i32 }))
+	}
+}
+
+impl Drop for CapabilityReport {
+	fn drop(&mut self) {
+		unsafe {
+			libc::close(self.read_fd);
+			libc::close(self.write_fd);
+		}
+	}
+}
+
+/// Resolves a capability name into its number. The name is case insensitive
+/// and the `CAP_` prefix is optional.
+pub fn resolve_capability(name: &str) -> Result<Capability, Error> {
+	let upper_name = name.to_uppercase();
+	let upper_name = if upper_name.starts_with("CAP_") { upper_name } else { format!("CAP_{}", upper_name) };
+
+	CAPABILITY_NAMES.iter()
+		.position(|&v| v == upper_name)
+		.map(|v| v as Capability)
+		.ok_or_else(|| Error::CapabilityNotFound(String::from(name)))
+}
+
+/// Resolves a list of capability names into a list of capability numbers.
+pub fn resolve_capabilities(names: &[String]) -> Result<Vec<Capability>, Error> {
+	let capabilities = names.iter().map(|v| resolve_capability(v)).collect::<Result<Vec<Capability>, Error>>()?;
+
+	debug!("Resolved capabilities {:?} to {:?}.", names, capabilities);
+
+	Ok(capabilities)
+}
+
+/// Prepares the current process for switching the user id. If keep_caps is set the
+/// permitted capabilities survive the switch of the user id. If lock is set, the
+/// securebits are set and locked. This prevents the executed program from regaining
+/// capabilities by being root or executing a setuid root binary.
+/// This function is meant to be called between fork and exec.
+pub fn prepare_securebits(keep_caps: bool, lock: bool) -> Result<(), CapabilityFailure> {
+	if lock {
+		let mut securebits = SECBIT_NOROOT | SECBIT_NOROOT_LOCKED | SECBIT_NO_SETUID_FIXUP_LOCKED | SECBIT_KEEP_CAPS_LOCKED;
+		if keep_caps { securebits |= SECBIT_KEEP_CAPS; }
+
+		if unsafe { libc::prctl(libc::PR_SET_SECUREBITS, securebits, 0, 0, 0) } != 0 {
+			return Err(CapabilityFailure::last_os_error(CapabilityStep::Securebits));
+		}
+	} else if keep_caps && unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0) } != 0 {
+		return Err(CapabilityFailure::last_os_error(CapabilityStep::KeepCaps));
+	}
+
+	Ok(())
+}
+
+/// Reduces the capabilities of the current process to the passed capabilities and
+/// raises them into the ambient set. That way they are kept accross exec.
+/// This function is meant to be called between fork and exec. Therefore it does
+/// not allocate any memory.
+pub fn set_ambient_capabilities(capabilities: &[Capability]) -> Result<(), CapabilityFailure> {
+	let mut header = CapUserHeader {
+		version: LINUX_CAPABILITY_VERSION_3,
+		pid: 0
+	};
+	let mut data = [CapUserData::default(); 2];
+
+	for &capability in capabilities {
+		let set = &mut data[(capability / 32) as usize];
+		let mask = 1 << (capability % 32);
+
+		set.effective |= mask;
+		set.permitted |= mask;
+		set.inheritable |= mask;
+	}
+
+	if unsafe { libc::syscall(libc::SYS_capset, &mut header as *mut CapUserHeader, data.as_ptr()) } != 0 {
+		return Err(CapabilityFailure::last_os_error(CapabilityStep::Capset));
+	}
+
+	for &capability in capabilities {
+		if unsafe { libc::prctl(libc::PR_CAP_AMBIENT, libc::PR_CAP_AMBIENT_RAISE as libc::c_ulong, capability as libc::c_ulong, 0, 0) } != 0 {
+			return Err(CapabilityFailure::last_os_error(CapabilityStep::Ambient(capability)));
+		}
+	}
+
+	Ok(())
+}
+
+/// Returns true if the process runs with elevated privileges. That is the case if it
+/// runs as root, via setuid/setgid or holds any capabilities (e.g. via setcap).
+pub fn is_privileged() -> io::Result<bool> {
+	if unsafe { libc::geteuid() == 0 || libc::geteuid() != libc::getuid() || libc::getegid() != libc::getgid() } {
+		return Ok(true);
+	}
+
+	let mut header = CapUserHeader {
+		version: LINUX_CAPABILITY_VERSION_3,
+		pid: 0
+	};
+	let mut data = [CapUserData::default(); 2];
+
+	if unsafe { libc::syscall(libc::SYS_capget, &mut header as *mut CapUserHeader, data.as_mut_ptr()) } != 0 {
+		Err(io::Error::last_os_error())
+	} else {
+		Ok(data.iter().any(|set| set.effective != 0 || set.permitted != 0))
+	}
+}
+
+/// Sets the no_new_privs flag. After this the executed program and its children
+/// can not gain privileges via setuid binaries or file capabilities.
+pub fn set_no_new_privs() -> io::Result<()> {
+	if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+		Err(io::Error::last_os_error())
+	} else {
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	/// Test that capability names are resolved with and without prefix and independent of case.
+	#[test]
+	fn res_capability() {
+		assert_eq!(resolve_capability("CAP_CHOWN").unwrap(), 0);
+		assert_eq!(resolve_capability("CAP_NET_BIND_SERVICE").unwrap(), 10);
+		assert_eq!(resolve_capability("net_bind_service").unwrap(), 10);
+		assert_eq!(resolve_capability("cap_sys_admin").unwrap(), 21);
+		assert_eq!(resolve_capability("CAP_CHECKPOINT_RESTORE").unwrap(), 40);
+
+		// Check that an unkown capability leads to an error
+		assert!(resolve_capability("CAP_UNKNOWN").is_err());
+	}
+
+	/// Verify that a failure reported by a child is received with the capability name.
+	#[test]
+	fn report_failure() {
+		use std::os::unix::process::CommandExt;
+		use std::process::Command;
+		use std::sync::Arc;
+
+		let report = Arc::new(CapabilityReport::new().unwrap());
+		assert!(report.receive().is_none());
+
+		let child_report = report.clone();
+		let mut command = Command::new("/usr/bin/true");
+		unsafe {
+			command.pre_exec(move || {
+				let failure = CapabilityFailure { step: CapabilityStep::Ambient(10), errno: libc::EPERM };
+				child_report.send(failure);
+				Err(failure.into())
+			});
+		}
+
+		assert_eq!(command.spawn().unwrap_err().raw_os_error(), Some(libc::EPERM));
+		match report.receive() {
+			Some(Error::CapabilityFailed(name, errno)) => assert_eq!((name.as_str(), errno), ("CAP_NET_BIND_SERVICE", libc::EPERM)),
+			other => panic!("Unexpected report {:?}.", other)
+		}
+	}
+}
@@ -1,15 +1,24 @@
 use std::ffi::{IntoStringError, NulError};
+use std::path::PathBuf;
 use std::io;
 use std::error;
 use std::fmt;
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
 	OsError(io::Error),
 	ConversionError(IntoStringError),
 	InvalidString(NulError),
 	UserNotFound(String),
 	GroupNotFound(String),
+	CapabilityNotFound(String),
+	CapabilityFailed(String, i32),
+	SymlinkRejected(PathBuf),
+	NotAFile(PathBuf),
+	FileReplaced(PathBuf),
+	SandboxNotPrepared,
+	Unsupported(String),
 	ExecFailed(i32)
 }
 
@@ -21,6 +30,13 @@ impl fmt::Display for Error {
 			Self::InvalidString(error) => write!(f, "Invalid string: {}", error),
 			Self::UserNotFound(user_name) => write!(f, "User {} not found.", user_name),
 			Self::GroupNotFound(group_name) => write!(f, "Group {} not found.", group_name),
+			Self::CapabilityNotFound(capability) => write!(f, "Capability {} not found.", capability),
+			Self::CapabilityFailed(capability, errno) => write!(f, "Setting {} failed: {}", capability, io::Error::from_raw_os_error(*errno)),
+			Self::SymlinkRejected(path) => write!(f, "{} is a symbolic link.", path.display()),
+			Self::NotAFile(path) => write!(f, "{} is not a file.", path.display()),
+			Self::FileReplaced(path) => write!(f, "{} was replaced while opening it.", path.display()),
+			Self::SandboxNotPrepared => write!(f, "Paths can only be allowed after restricting them."),
+			Self::Unsupported(feature) => write!(f, "{} is not supported on this architecture.", feature),
 			Self::ExecFailed(result_code) => write!(f, "Process failed with exit code {}.", result_code)
 		}
 	}
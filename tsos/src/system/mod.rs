@@ -1,20 +1,37 @@
-use libc;
 use log::debug;
 use std::path::Path;
 use std::ffi::CString;
 use std::ptr;
 use std::io;
 use std::mem::MaybeUninit;
+use std::os::unix::io::RawFd;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Child, ExitStatus};
+use std::time::{Duration, Instant};
 
 mod tempdir;
 mod error;
 mod ramfs;
 mod permissions;
+mod privileges;
+mod capabilities;
+mod sandbox;
+mod pinned;
+mod secret;
+mod readonce;
+mod sha256;
 
 pub use tempdir::TempDir;
 pub use error::Error;
-pub use ramfs::RamFs;
+pub use ramfs::{RamFs, DetachedRamFs};
 pub use permissions::copy_perms_and_owners;
+pub use privileges::{user_groups, switch_ids};
+pub use pinned::PinnedFile;
+pub use secret::{SecretBuffer, SecretString, Undumpable};
+pub use readonce::ReadOnce;
+pub use sha256::sha256_hex;
+pub use sandbox::{Sandbox, Access};
+pub use capabilities::{resolve_capabilities, CapabilityReport, prepare_securebits, set_ambient_capabilities, set_no_new_privs, is_privileged};
 
 pub fn bind(source: &Path, target: &Path) -> Result<(), error::Error> {
 		let c_source = CString::new(source.to_str().unwrap())?;
@@ -29,6 +46,46 @@ pub fn bind(source: &Path, target: &Path) -> Result<(), error::Error> {
 		}
 }
 
+/// Makes sure the command inherits the passed file descriptor even if it is marked
+/// as close-on-exec.
+pub fn pass_fd(command: &mut Command, fd: RawFd) {
+	unsafe {
+		command.pre_exec(move || {
+			if libc::fcntl(fd, libc::F_SETFD, 0) < 0 {
+				Err(io::Error::last_os_error())
+			} else {
+				Ok(())
+			}
+		});
+	}
+}
+
+/// Waits for the child to terminate. Returns None if the child is still running after
+/// the timeout.
+pub fn wait_timeout(child: &mut Child, timeout: Duration) -> Result<Option<ExitStatus>, Error> {
+	let pid_fd = unsafe { libc::syscall(libc::SYS_pidfd_open, child.id(), 0) } as RawFd;
+	if pid_fd < 0 {
+		return Err(Error::OsError(io::Error::last_os_error()));
+	}
+
+	let deadline = Instant::now() + timeout;
+	let result = loop {
+		let remaining = deadline.saturating_duration_since(Instant::now()).as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+		let mut poll_fd = libc::pollfd { fd: pid_fd, events: libc::POLLIN, revents: 0 };
+
+		match unsafe { libc::poll(&mut poll_fd, 1, remaining) } {
+			ready if ready >= 0 => break Ok(ready > 0),
+			_ => {
+				let error = io::Error::last_os_error();
+				if error.kind() != io::ErrorKind::Interrupted { break Err(error); }
+			}
+		}
+	};
+	unsafe { libc::close(pid_fd) };
+
+	if result? { Ok(Some(child.wait()?)) } else { Ok(None) }
+}
+
 pub fn unshare_mount_ns() -> io::Result<()> {
 	debug!("Unshare mount namespaces...");
 
@@ -47,6 +104,56 @@ pub fn unshare_mount_ns() -> io::Result<()> {
 	}
 }
 
+/// Returns a random number from the random number generator of the kernel.
+pub fn random_u64() -> io::Result<u64> {
+	let mut value = [0u8; 8];
+	if unsafe { libc::getrandom(value.as_mut_ptr() as *mut libc::c_void, value.len(), 0) } != value.len() as isize {
+		Err(io::Error::last_os_error())
+	} else {
+		Ok(u64::from_ne_bytes(value))
+	}
+}
+
+/// Returns the name of a signal (e.g. `SIGKILL`). Unknown signals are returned as number.
+pub fn signal_name(signal: i32) -> String {
+	let name = match signal {
+		libc::SIGHUP => "SIGHUP",
+		libc::SIGINT => "SIGINT",
+		libc::SIGQUIT => "SIGQUIT",
+		libc::SIGILL => "SIGILL",
+		libc::SIGTRAP => "SIGTRAP",
+		libc::SIGABRT => "SIGABRT",
+		libc::SIGBUS => "SIGBUS",
+		libc::SIGFPE => "SIGFPE",
+		libc::SIGKILL => "SIGKILL",
+		libc::SIGUSR1 => "SIGUSR1",
+		libc::SIGSEGV => "SIGSEGV",
+		libc::SIGUSR2 => "SIGUSR2",
+		libc::SIGPIPE => "SIGPIPE",
+		libc::SIGALRM => "SIGALRM",
+		libc::SIGTERM => "SIGTERM",
+		libc::SIGCHLD => "SIGCHLD",
+		libc::SIGCONT => "SIGCONT",
+		libc::SIGSTOP => "SIGSTOP",
+		libc::SIGTSTP => "SIGTSTP",
+		libc::SIGTTIN => "SIGTTIN",
+		libc::SIGTTOU => "SIGTTOU",
+		libc::SIGURG => "SIGURG",
+		libc::SIGXCPU => "SIGXCPU",
+		libc::SIGXFSZ => "SIGXFSZ",
+		libc::SIGVTALRM => "SIGVTALRM",
+		libc::SIGPROF => "SIGPROF",
+		libc::SIGWINCH => "SIGWINCH",
+		libc::SIGIO => "SIGIO",
+		libc::SIGPWR => "SIGPWR",
+		libc::SIGSYS => "SIGSYS",
+		signal if signal >= libc::SIGRTMIN() && signal <= libc::SIGRTMAX() => return format!("SIGRTMIN+{}", signal - libc::SIGRTMIN()),
+		signal => return format!("signal {}", signal)
+	};
+
+	String::from(name)
+}
+
 pub type UId = libc::uid_t;
 pub type GId = libc::gid_t;
 
@@ -118,6 +225,7 @@ pub fn resolve_group(group_name: &str) -> Result<GId, error::Error> {
 	}
 }
 
+#[allow(clippy::from_str_radix_10)]
 #[cfg(test)]
 mod test {
 	use super::*;
@@ -169,4 +277,25 @@ mod test {
 		// Check that an unkown group leads to an error
 		assert!(resolve_group("u_n-k,o.w+n").is_err());
 	}
+
+	/// Test that waiting for a child stops after the timeout.
+	#[test]
+	fn wait_child_timeout() {
+		let mut child = Command::new("/usr/bin/sleep").arg("5").spawn().unwrap();
+		assert!(wait_timeout(&mut child, Duration::from_millis(100)).unwrap().is_none(), "Child terminated early");
+		child.kill().unwrap();
+		child.wait().unwrap();
+
+		let mut child = Command::new("/usr/bin/true").spawn().unwrap();
+		assert!(wait_timeout(&mut child, Duration::from_secs(5)).unwrap().unwrap().success(), "Child failed");
+	}
+
+	/// Verify the names of known, real-time and unknown signals.
+	#[test]
+	fn signal_names() {
+		assert_eq!(signal_name(libc::SIGKILL), "SIGKILL");
+		assert_eq!(signal_name(libc::SIGSEGV), "SIGSEGV");
+		assert_eq!(signal_name(libc::SIGRTMIN() + 2), "SIGRTMIN+2");
+		assert_eq!(signal_name(200), "signal 200");
+	}
 }
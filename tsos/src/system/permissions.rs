@@ -1,4 +1,3 @@
-use libc;
 use log::{debug};
 use std::path::Path;
 use std::ffi::CString;
@@ -16,7 +15,7 @@ struct ErrorContext { _private: [u8; 0] }
 
 #[cfg(feature = "acl")]
 #[link(name = "acl")]
-extern {
+extern "C" {
 	fn perm_copy_file (source: *const libc::c_char, destination: *const libc::c_char, ctx: *mut ErrorContext) -> libc::c_int;
 }
 
@@ -62,7 +61,7 @@ pub fn copy_perms_and_owners(src: &Path, dst: &Path) -> io::Result<()>{
 	let c_dst = CString::new(dst.to_str().unwrap())?;
 	let mdata = src.metadata()?;
 
-	copy_permissions(&src, &mdata, &dst)?;
+	copy_permissions(src, &mdata, dst)?;
 
 	debug!("Copying ownership information from {} => {}...", src.display(), dst.display());
 
@@ -74,6 +73,7 @@ pub fn copy_perms_and_owners(src: &Path, dst: &Path) -> io::Result<()>{
 	}
 }
 
+#[allow(clippy::single_component_path_imports)]
 #[cfg(test)]
 mod test {
 	use super::*;
@@ -0,0 +1,244 @@
+use log::debug;
+use std::path::{Path, PathBuf};
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::mem::MaybeUninit;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::process::Command;
+
+use super::Error;
+
+/// A file that is opened once and afterwards only referenced via its file descriptor.
+/// All operations done via the path returned by as_ref (`/proc/self/fd/<fd>`) refer
+/// to the same inode, even if the original path is replaced in the meantime.
+pub struct PinnedFile {
+	fd: RawFd,
+	path: PathBuf,
+	inode: libc::ino64_t
+}
+
+impl Drop for PinnedFile {
+	fn drop(&mut self) {
+		unsafe { libc::close(self.fd) };
+	}
+}
+
+impl AsRef<Path> for PinnedFile {
+	fn as_ref(&self) -> &Path {
+		&self.path
+	}
+}
+
+fn open_path(dir_fd: RawFd, c_path: &CString, flags: libc::c_int) -> Result<(RawFd, libc::stat64), Error> {
+	let fd = unsafe { libc::openat(dir_fd, c_path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC | flags) };
+	if fd < 0 {
+		return Err(Error::OsError(io::Error::last_os_error()));
+	}
+
+	let mut stat = MaybeUninit::<libc::stat64>::zeroed();
+	if unsafe { libc::fstat64(fd, stat.as_mut_ptr()) } != 0 {
+		let error = io::Error::last_os_error();
+		unsafe { libc::close(fd) };
+		Err(Error::OsError(error))
+	} else {
+		Ok((fd, unsafe { stat.assume_init() }))
+	}
+}
+
+/// Reads the target of the symbolic link referenced by the file descriptor.
+fn read_link(link_fd: RawFd) -> Result<CString, Error> {
+	let mut buffer = vec![0u8; libc::PATH_MAX as usize];
+	let length = unsafe { libc::readlinkat(link_fd, b"\0".as_ptr() as *const libc::c_char, buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) };
+	if length < 0 {
+		return Err(Error::OsError(io::Error::last_os_error()));
+	}
+
+	buffer.truncate(length as usize);
+	Ok(CString::new(buffer)?)
+}
+
+/// Opens the entry name within the directory dir_fd. A symbolic link is resolved
+/// via the content of the opened link relative to dir_fd. Afterwards the entry must
+/// still be the same link. That way the path is never resolved a second time.
+fn open_entry(dir_fd: RawFd, path: &Path, name: &CString, follow_symlinks: bool) -> Result<(RawFd, libc::stat64), Error> {
+	let (link_fd, link_stat) = open_path(dir_fd, name, libc::O_NOFOLLOW)?;
+	if link_stat.st_mode & libc::S_IFMT != libc::S_IFLNK {
+		return Ok((link_fd, link_stat));
+	}
+
+	if !follow_symlinks {
+		unsafe { libc::close(link_fd) };
+		return Err(Error::SymlinkRejected(PathBuf::from(path)));
+	}
+
+	let target = read_link(link_fd);
+	unsafe { libc::close(link_fd) };
+	let target = target?;
+
+	debug!("Following symbolic link {} to {}...", path.display(), target.to_string_lossy());
+
+	let (fd, stat) = open_path(dir_fd, &target, 0)?;
+
+	let mut current = MaybeUninit::<libc::stat64>::zeroed();
+	let replaced = unsafe { libc::fstatat64(dir_fd, name.as_ptr(), current.as_mut_ptr(), libc::AT_SYMLINK_NOFOLLOW) } != 0 || {
+		let current = unsafe { current.assume_init() };
+		current.st_dev != link_stat.st_dev || current.st_ino != link_stat.st_ino
+	};
+	if replaced {
+		unsafe { libc::close(fd) };
+		return Err(Error::FileReplaced(PathBuf::from(path)));
+	}
+
+	Ok((fd, stat))
+}
+
+impl PinnedFile {
+	/// Opens a regular file. If the path refers to a symbolic link, the link is
+	/// followed if follow_symlinks is set. Otherwise an error is returned.
+	/// The link is resolved relative to its already opened directory.
+	pub fn open(path: &Path, follow_symlinks: bool) -> Result<Self, Error> {
+		let (fd, stat) = match (path.parent(), path.file_name()) {
+			(Some(parent), Some(name)) => {
+				let parent = if parent.as_os_str().is_empty() { Path::new(".") } else { parent };
+				let (dir_fd, _) = open_path(libc::AT_FDCWD, &CString::new(parent.as_os_str().as_bytes())?, libc::O_DIRECTORY)?;
+				let result = open_entry(dir_fd, path, &CString::new(name.as_bytes())?, follow_symlinks);
+				unsafe { libc::close(dir_fd) };
+				result?
+			},
+			_ => open_path(libc::AT_FDCWD, &CString::new(path.as_os_str().as_bytes())?, 0)?
+		};
+
+		if stat.st_mode & libc::S_IFMT != libc::S_IFREG {
+			unsafe { libc::close(fd) };
+			return Err(Error::NotAFile(PathBuf::from(path)));
+		}
+
+		let pinned = PathBuf::from(format!("/proc/self/fd/{}", fd));
+
+		debug!("Pinned {} as {}.", path.display(), pinned.display());
+
+		Ok(Self {
+			fd,
+			path: pinned,
+			inode: stat.st_ino
+		})
+	}
+
+	/// Copies the content into a sealed memfd and pins the copy. The copy can not be
+	/// changed anymore. Unlike the original file, whose content can still be written in
+	/// place, executing the copy runs exactly what was read before.
+	pub fn sealed_copy(&self) -> Result<Self, Error> {
+		let name = CString::new("tsos-sealed")?;
+		let mut fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING | libc::MFD_EXEC) };
+		if fd < 0 && io::Error::last_os_error().raw_os_error() == Some(libc::EINVAL) {
+			// Kernels before 6.3 do not know MFD_EXEC. Their memfds are executable anyway.
+			fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING) };
+		}
+		if fd < 0 {
+			return Err(Error::OsError(io::Error::last_os_error()));
+		}
+		let mut copy = unsafe { File::from_raw_fd(fd) };
+
+		io::copy(&mut File::open(&self.path)?, &mut copy)?;
+		if unsafe { libc::fcntl(fd, libc::F_ADD_SEALS, libc::F_SEAL_SEAL | libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_WRITE) } != 0 {
+			return Err(Error::OsError(io::Error::last_os_error()));
+		}
+
+		// A file can not be executed while it is open for writing. Therefore the copy
+		// is pinned via a new descriptor and the writable one is closed.
+		let (sealed_fd, stat) = open_path(libc::AT_FDCWD, &CString::new(format!("/proc/self/fd/{}", fd))?, 0)?;
+		drop(copy);
+
+		let sealed = PathBuf::from(format!("/proc/self/fd/{}", sealed_fd));
+
+		debug!("Sealed a copy of {} as {}.", self.path.display(), sealed.display());
+
+		Ok(Self {
+			fd: sealed_fd,
+			path: sealed,
+			inode: stat.st_ino
+		})
+	}
+
+	/// Inode number of the pinned file.
+	pub fn inode(&self) -> u64 {
+		self.inode
+	}
+
+	/// Makes sure the command inherits the file descriptor. That way the path of
+	/// the pinned file is valid within the child process.
+	pub fn pass_to(&self, command: &mut Command) {
+		super::pass_fd(command, self.fd);
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use super::super::TempDir;
+	use std::fs::{File, read_to_string, rename};
+	use std::io::Write;
+	use std::os::unix::fs::symlink;
+
+	/// Test that the pinned file stays the same if the original path is replaced.
+	#[test]
+	fn pinned_replace() {
+		let mut tmp = TempDir::new("test").unwrap();
+		let file = tmp.create_file("file").unwrap();
+		let other = tmp.create_file("other").unwrap();
+		File::create(&file).unwrap().write_all(b"original").unwrap();
+		File::create(&other).unwrap().write_all(b"replaced").unwrap();
+
+		let pinned = PinnedFile::open(&file, false).unwrap();
+		rename(&other, &file).unwrap();
+
+		assert_eq!(read_to_string(&pinned).unwrap(), "original");
+	}
+
+	/// Test the handling of symbolic links and other file types.
+	#[test]
+	fn pinned_symlink() {
+		let mut tmp = TempDir::new("test").unwrap();
+		let file = tmp.create_file("file").unwrap();
+		File::create(&file).unwrap().write_all(b"content").unwrap();
+
+		let mut link = PathBuf::from(tmp.as_ref());
+		link.push("link");
+		symlink(&file, &link).unwrap();
+
+		match PinnedFile::open(&link, false) {
+			Err(Error::SymlinkRejected(_)) => (),
+			_ => panic!("Symbolic link was not rejected.")
+		}
+
+		assert_eq!(read_to_string(PinnedFile::open(&link, true).unwrap()).unwrap(), "content");
+
+		// Relative links are resolved relative to the directory of the link.
+		let mut relative = PathBuf::from(tmp.as_ref());
+		relative.push("relative");
+		symlink(file.file_name().unwrap(), &relative).unwrap();
+		assert_eq!(read_to_string(PinnedFile::open(&relative, true).unwrap()).unwrap(), "content");
+
+		match PinnedFile::open(tmp.as_ref(), true) {
+			Err(Error::NotAFile(_)) => (),
+			_ => panic!("Directory was not rejected.")
+		}
+	}
+
+	/// Test that changing the original file in place does not change the sealed copy
+	/// and that the copy can not be written.
+	#[test]
+	fn pinned_sealed() {
+		let mut tmp = TempDir::new("test").unwrap();
+		let file = tmp.create_file("file").unwrap();
+		File::create(&file).unwrap().write_all(b"original").unwrap();
+
+		let sealed = PinnedFile::open(&file, false).unwrap().sealed_copy().unwrap();
+		std::fs::OpenOptions::new().write(true).open(&file).unwrap().write_all(b"replaced").unwrap();
+
+		assert_eq!(read_to_string(&sealed).unwrap(), "original");
+		assert!(std::fs::OpenOptions::new().write(true).open(&sealed).and_then(|mut v| v.write_all(b"x")).is_err(), "Sealed copy was written.");
+	}
+}
@@ -0,0 +1,101 @@
+use log::debug;
+use std::io;
+use std::mem::MaybeUninit;
+
+use super::{Error, UId, GId};
+
+/// Determines the supplementary groups of the user with the passed uid.
+/// The passed group id is always part of the returned list. This is the same
+/// list initgroups would set for this user.
+pub fn user_groups(uid: UId, gid: GId) -> Result<Vec<GId>, Error> {
+	let mut user_info = MaybeUninit::<libc::passwd>::zeroed();
+	let mut result: *mut libc::passwd = std::ptr::null_mut();
+
+	// Dertermin the necessary buffer size for the getpwuid call.
+	// If the size could not be determined, use 16 K
+	let buffer_size = unsafe { libc::sysconf(libc::_SC_GETPW_R_SIZE_MAX) }.max(16384) as usize;
+	let mut buffer = vec![0 as libc::c_char; buffer_size];
+
+	let error = unsafe { libc::getpwuid_r(uid, user_info.as_mut_ptr(), buffer.as_mut_ptr(), buffer_size, &mut result) };
+	if error != 0 {
+		return Err(Error::OsError(io::Error::from_raw_os_error(error)));
+	}
+	if result.is_null() {
+		return Err(Error::UserNotFound(format!("uid {}", uid)));
+	}
+
+	// The user name points into buffer. It stays valid as long as buffer is alive.
+	let user_info = unsafe { user_info.assume_init() };
+
+	// Start with a reasonable group count. If it is too small getgrouplist
+	// returns the necessary size and we try again.
+	let mut group_count: libc::c_int = 32;
+	loop {
+		let mut groups = vec![0 as GId; group_count as usize];
+		let mut new_count = group_count;
+
+		if unsafe { libc::getgrouplist(user_info.pw_name, gid, groups.as_mut_ptr(), &mut new_count) } >= 0 {
+			groups.truncate(new_count as usize);
+			debug!("User with uid {} is member of the groups {:?}.", uid, groups);
+			return Ok(groups);
+		}
+
+		// Make sure we do not loop forever if the group count does not grow.
+		if new_count <= group_count { group_count *= 2; } else { group_count = new_count; }
+	}
+}
+
+/// Switches the user id, group id and the supplementary groups of the current process.
+/// This function is meant to be called between fork and exec. Therefore it does
+/// not allocate any memory.
+pub fn switch_ids(uid: Option<UId>, gid: Option<GId>, groups: Option<&[GId]>) -> io::Result<()> {
+	// The supplementary groups and the group id must be set before the user id.
+	// After the user id is changed, we lose the privileges to do so.
+	if let Some(groups) = groups {
+		if unsafe { libc::setgroups(groups.len(), groups.as_ptr()) } != 0 {
+			return Err(io::Error::last_os_error());
+		}
+	}
+
+	if let Some(gid) = gid {
+		if unsafe { libc::setgid(gid) } != 0 {
+			return Err(io::Error::last_os_error());
+		}
+	}
+
+	if let Some(uid) = uid {
+		if unsafe { libc::setuid(uid) } != 0 {
+			return Err(io::Error::last_os_error());
+		}
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::process::Command;
+
+	/// Test that the supplementary groups of a user are determined correctly.
+	/// The reference values are taken from the output of `id -G`.
+	#[test]
+	fn res_user_groups() {
+		for user_name in &[ "root", "nobody", "daemon" ] {
+			let uid = Command::new("id").arg("-u").arg(user_name).output().unwrap();
+			let uid = String::from_utf8_lossy(&uid.stdout).trim().parse::<u32>().unwrap();
+			let gid = Command::new("id").arg("-g").arg(user_name).output().unwrap();
+			let gid = String::from_utf8_lossy(&gid.stdout).trim().parse::<u32>().unwrap();
+			let id_groups = Command::new("id").arg("-G").arg(user_name).output().unwrap();
+
+			let mut reference: Vec<u32> = String::from_utf8_lossy(&id_groups.stdout).split_whitespace().map(|v| v.parse::<u32>().unwrap()).collect();
+			reference.sort_unstable();
+
+			let mut groups = user_groups(uid, gid).unwrap();
+			groups.sort_unstable();
+			groups.dedup();
+
+			assert_eq!(groups, reference, "Wrong groups for user {}.", user_name);
+		}
+	}
+}
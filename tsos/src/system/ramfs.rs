@@ -1,12 +1,27 @@
-use libc;
 use log::{debug, warn};
 use std::path::{PathBuf, Path};
 use std::ffi::CString;
 use std::ptr;
 use std::io;
+use std::os::unix::io::RawFd;
+use std::process::Command;
+
+use super::Error;
+
+// Constants of the new mount API from linux/mount.h
+const FSOPEN_CLOEXEC: libc::c_uint = 0x01;
+const FSCONFIG_SET_STRING: libc::c_uint = 1;
+const FSCONFIG_CMD_CREATE: libc::c_uint = 6;
+const FSMOUNT_CLOEXEC: libc::c_uint = 0x01;
+const MOUNT_ATTR_NODEV: libc::c_uint = 0x04;
+const MOUNT_ATTR_NOEXEC: libc::c_uint = 0x08;
+const OPEN_TREE_CLONE: libc::c_uint = 0x01;
+const MOVE_MOUNT_F_EMPTY_PATH: libc::c_uint = 0x04;
+const MOVE_MOUNT_T_SYMLINKS: libc::c_uint = 0x10;
 
 pub struct RamFs {
-	mount_point: PathBuf
+	mount_point: PathBuf,
+	lazy: bool
 }
 
 impl Drop for RamFs {
@@ -16,7 +31,8 @@ impl Drop for RamFs {
 
 		debug!("Unmounting {}...", self.mount_point.display());
 
-		if unsafe { libc::umount(c_path.as_ptr()) } < 0 {
+		let flags = if self.lazy { libc::MNT_DETACH } else { 0 };
+		if unsafe { libc::umount2(c_path.as_ptr(), flags) } < 0 {
 			warn!("Unmounting {} failed with error {}", self.mount_point.display(), io::Error::last_os_error());
 		}
 	}
@@ -42,10 +58,129 @@ impl RamFs {
 			Err(io::Error::last_os_error())
 		} else {
 			Ok(Self{
-				mount_point: PathBuf::from(path)
+				mount_point: PathBuf::from(path),
+				lazy: false
 			})
 		}
 	}
+
+	/// Detaches the ramfs lazily on drop. Files within the ramfs that are still open (e.g.
+	/// the read once files watched by the supervisor) would otherwise keep the mount busy.
+	/// The bound files stay available anyway.
+	pub fn unmount_lazily(&mut self) {
+		self.lazy = true;
+	}
+}
+
+/// A ramfs that is created with the new mount API and never attached to a path.
+/// The files within this ramfs are only reachable via file descriptors. The ramfs
+/// vanishes as soon as all file descriptors are closed and all bind mounts of its
+/// files are gone. Therefore nothing can leak if TSoS terminates unexpectedly.
+pub struct DetachedRamFs {
+	mount_fd: RawFd,
+	files: Vec<(PathBuf, RawFd)>
+}
+
+impl Drop for DetachedRamFs {
+	fn drop(&mut self) {
+		debug!("Closing detached ramfs...");
+
+		for &(_, file_fd) in self.files.iter() {
+			unsafe { libc::close(file_fd) };
+		}
+		unsafe { libc::close(self.mount_fd) };
+	}
+}
+
+impl DetachedRamFs {
+	pub fn new(source_tag: &str) -> Result<Self, Error> {
+		let c_fstype = CString::new("ramfs")?;
+		let c_source = CString::new("source")?;
+		let c_source_tag = CString::new(source_tag)?;
+		let c_mode = CString::new("mode")?;
+		let c_mode_value = CString::new("701")?;
+
+		debug!("Creating detached ramfs...");
+
+		let fs_fd = unsafe { libc::syscall(libc::SYS_fsopen, c_fstype.as_ptr(), FSOPEN_CLOEXEC) } as RawFd;
+		if fs_fd < 0 {
+			return Err(Error::OsError(io::Error::last_os_error()));
+		}
+
+		let mount_fd = unsafe {
+			if libc::syscall(libc::SYS_fsconfig, fs_fd, FSCONFIG_SET_STRING, c_source.as_ptr(), c_source_tag.as_ptr(), 0) < 0 ||
+				libc::syscall(libc::SYS_fsconfig, fs_fd, FSCONFIG_SET_STRING, c_mode.as_ptr(), c_mode_value.as_ptr(), 0) < 0 ||
+				libc::syscall(libc::SYS_fsconfig, fs_fd, FSCONFIG_CMD_CREATE, ptr::null::<libc::c_char>(), ptr::null::<libc::c_void>(), 0) < 0 {
+				-1
+			} else {
+				libc::syscall(libc::SYS_fsmount, fs_fd, FSMOUNT_CLOEXEC, MOUNT_ATTR_NODEV | MOUNT_ATTR_NOEXEC) as RawFd
+			}
+		};
+		let error = io::Error::last_os_error();
+		unsafe { libc::close(fs_fd) };
+
+		if mount_fd < 0 {
+			Err(Error::OsError(error))
+		} else {
+			Ok(Self {
+				mount_fd,
+				files: Vec::new()
+			})
+		}
+	}
+
+	/// Creates an empty file within the ramfs. The returned path refers to the file
+	/// via its file descriptor (`/proc/self/fd/<fd>`). To use it within a child process
+	/// the file descriptor must be passed on by calling pass_file.
+	pub fn create_file(&mut self, prefix: &str) -> Result<PathBuf, Error> {
+		let c_file_name = CString::new(format!("{}-{:08x}", prefix, self.files.len()))?;
+
+		let file_fd = unsafe { libc::openat(self.mount_fd, c_file_name.as_ptr(), libc::O_CREAT|libc::O_EXCL|libc::O_NOFOLLOW|libc::O_RDWR|libc::O_CLOEXEC, libc::S_IRWXU) };
+		if file_fd < 0 {
+			return Err(Error::OsError(io::Error::last_os_error()));
+		}
+
+		let file = PathBuf::from(format!("/proc/self/fd/{}", file_fd));
+		self.files.push((file.clone(), file_fd));
+
+		debug!("Allocated detached file {}.", file.display());
+
+		Ok(file)
+	}
+
+	/// Makes sure the command inherits the file descriptor of a file created by
+	/// create_file. That way the path of the file is valid within the child process.
+	pub fn pass_file(&self, command: &mut Command, file: &Path) {
+		if let Some(&(_, file_fd)) = self.files.iter().find(|(path, _)| path == file) {
+			super::pass_fd(command, file_fd);
+		}
+	}
+
+	/// Binds a file created by create_file onto the target path.
+	pub fn bind(&self, source: &Path, target: &Path) -> Result<(), Error> {
+		let c_source = CString::new(source.to_str().unwrap())?;
+		let c_target = CString::new(target.to_str().unwrap())?;
+		let c_empty = CString::new("")?;
+
+		debug!("Binding {} on {}...", source.display(), target.display());
+
+		// Clone the file into a new detached mount and move this mount onto the target.
+		// Symbolic links within the target are followed to support pinned files.
+		let tree_fd = unsafe { libc::syscall(libc::SYS_open_tree, libc::AT_FDCWD, c_source.as_ptr(), OPEN_TREE_CLONE | libc::O_CLOEXEC as libc::c_uint) } as RawFd;
+		if tree_fd < 0 {
+			return Err(Error::OsError(io::Error::last_os_error()));
+		}
+
+		let result = unsafe { libc::syscall(libc::SYS_move_mount, tree_fd, c_empty.as_ptr(), libc::AT_FDCWD, c_target.as_ptr(), MOVE_MOUNT_F_EMPTY_PATH | MOVE_MOUNT_T_SYMLINKS) };
+		let error = io::Error::last_os_error();
+		unsafe { libc::close(tree_fd) };
+
+		if result < 0 {
+			Err(Error::OsError(error))
+		} else {
+			Ok(())
+		}
+	}
 }
 
 #[cfg(test)]
@@ -54,6 +189,9 @@ mod test {
 	use super::super::TempDir;
 	use std::fs::read_dir;
 	use std::fs::File;
+	use std::fs::read_to_string;
+	use std::io::Write;
+	use std::thread;
 
 	// Test mounting and unmounting RamFs
 	#[test]
@@ -75,4 +213,28 @@ mod test {
 
 		assert_eq!(read_dir(&tmp).unwrap().count(), 0, "Mountpoint not empty after unmount");
 	}
-}
\ No newline at end of file
+
+	// Test binding a file from a detached ramfs.
+	#[test]
+	fn detached_bind() {
+		let mut tmp = TempDir::new("test").unwrap();
+		let target = tmp.create_file("target").unwrap();
+		let thread_target = target.clone();
+
+		// Do this within a thread with its own mount namespace to not disturb the system.
+		thread::spawn(move || {
+			crate::system::unshare_mount_ns().unwrap();
+
+			let mut ramfs = DetachedRamFs::new("testfs").unwrap();
+			let file = ramfs.create_file("test").unwrap();
+			File::create(&file).unwrap().write_all(b"detached").unwrap();
+			ramfs.bind(&file, &thread_target).unwrap();
+			drop(ramfs);
+
+			assert_eq!(read_to_string(&thread_target).unwrap(), "detached", "Bound file has wrong content");
+		}).join().unwrap();
+
+		// The bind mount was only visible within the thread.
+		assert_eq!(read_to_string(&target).unwrap(), "", "Bind mount leaked");
+	}
+}
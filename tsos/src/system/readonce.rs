@@ -0,0 +1,280 @@
+use log::{debug, Level, Record};
+use std::path::Path;
+use std::ffi::CString;
+use std::fmt;
+use std::mem::size_of;
+use std::ptr;
+use std::io;
+use std::os::unix::io::RawFd;
+
+use journal_logger::PreparedRecord;
+
+use super::{Error, Undumpable};
+
+/// A file whose content is removed as soon as it was read once. The messages are
+/// prepared in advance. The supervisor must not allocate memory after fork.
+struct Watched {
+	wd: libc::c_int,
+	fd: RawFd,
+	read_message: Option<PreparedRecord>,
+	failed_message: Option<PreparedRecord>
+}
+
+/// Formats a message of the supervisor for the installed logger. The supervisor can send
+/// it without using the logger. Loggers that can not prepare records get a plain line
+/// on stderr. Returns None if the logger drops the message.
+fn prepare_message(level: Level, key_values: &[(&str, &str)], message: fmt::Arguments<'_>) -> Option<PreparedRecord> {
+	let record = Record::builder()
+		.args(message)
+		.level(level)
+		.target(module_path!())
+		.module_path_static(Some(module_path!()))
+		.file_static(Some(file!()))
+		.line(Some(line!()))
+		.key_values(&key_values)
+		.build();
+	if !log::logger().enabled(record.metadata()) {
+		return None;
+	}
+
+	Some(journal_logger::prepare(&record).unwrap_or_else(|| PreparedRecord::stderr(&record)))
+}
+
+/// Sends a prepared message. Errors can not be reported anywhere else.
+fn send(message: &mut Option<PreparedRecord>) {
+	if let Some(message) = message { let _ = message.send(); }
+}
+
+/// Removes the content of secret files after the service has read them. The files are
+/// watched by a supervisor process that is started by supervise and runs next to the
+/// service until all files were read or the service terminates.
+pub struct ReadOnce {
+	inotify_fd: RawFd,
+	files: Vec<Watched>
+}
+
+impl Drop for ReadOnce {
+	fn drop(&mut self) {
+		for file in self.files.iter() {
+			unsafe { libc::close(file.fd) };
+		}
+		unsafe { libc::close(self.inotify_fd) };
+	}
+}
+
+/// Overwrites the content of the file with zeros and truncates it.
+fn clear_file(fd: RawFd) -> io::Result<()> {
+	let zeros = [0u8; 4096];
+	let mut offset: libc::off_t = 0;
+	let size = unsafe { libc::lseek(fd, 0, libc::SEEK_END) };
+	if size < 0 {
+		return Err(io::Error::last_os_error());
+	}
+
+	while offset < size {
+		let length = ((size - offset) as usize).min(zeros.len());
+		let written = unsafe { libc::pwrite(fd, zeros.as_ptr() as *const libc::c_void, length, offset) };
+		if written < 0 {
+			return Err(io::Error::last_os_error());
+		}
+		offset += written as libc::off_t;
+	}
+
+	if unsafe { libc::ftruncate(fd, 0) } != 0 {
+		Err(io::Error::last_os_error())
+	} else {
+		Ok(())
+	}
+}
+
+impl ReadOnce {
+	pub fn new() -> Result<Self, Error> {
+		let inotify_fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+		if inotify_fd < 0 {
+			Err(Error::OsError(io::Error::last_os_error()))
+		} else {
+			Ok(Self {
+				inotify_fd,
+				files: Vec::new()
+			})
+		}
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.files.is_empty()
+	}
+
+	/// Watches the file created by the secret provider. It is cleared after it was
+	/// opened for reading and closed again. The provider and template are only used
+	/// for logging.
+	pub fn add(&mut self, file: &Path, provider: &str, template: &str) -> Result<(), Error> {
+		let c_file = CString::new(file.to_str().unwrap())?;
+
+		let fd = unsafe { libc::open(c_file.as_ptr(), libc::O_RDWR | libc::O_CLOEXEC) };
+		if fd < 0 {
+			return Err(Error::OsError(io::Error::last_os_error()));
+		}
+
+		// Watch the inode via our own file descriptor. That way the watch stays valid
+		// even if the path of the file vanishes.
+		let c_fd_path = CString::new(format!("/proc/self/fd/{}", fd))?;
+		let wd = unsafe { libc::inotify_add_watch(self.inotify_fd, c_fd_path.as_ptr(), libc::IN_CLOSE_NOWRITE) };
+		if wd < 0 {
+			let error = io::Error::last_os_error();
+			unsafe { libc::close(fd) };
+			return Err(Error::OsError(error));
+		}
+
+		debug!("Watching {} for the first read.", template);
+
+		let key_values = [ ("tsos_provider", provider), ("tsos_template", template) ];
+		self.files.push(Watched {
+			wd,
+			fd,
+			read_message: prepare_message(Level::Info, &key_values, format_args!("Secret file {} was read. Removing its content.", template)),
+			failed_message: prepare_message(Level::Warn, &key_values, format_args!("Removing the content of {} failed.", template))
+		});
+
+		Ok(())
+	}
+
+	/// Clears the watched files as soon as they were read. Returns if all files were
+	/// cleared or the process referenced by the pidfd terminated. This runs within the
+	/// forked supervisor. Other threads may have held the allocator or the logger while
+	/// forking. Therefore it neither allocates memory nor uses the logger. It only sends
+	/// the messages prepared by add.
+	pub fn run(&mut self, service_fd: RawFd) -> Result<(), Error> {
+		let mut buffer = [0u8; 4096];
+		let mut remaining = self.files.len();
+
+		while remaining > 0 {
+			let mut poll_fds = [
+				libc::pollfd { fd: self.inotify_fd, events: libc::POLLIN, revents: 0 },
+				libc::pollfd { fd: service_fd, events: libc::POLLIN, revents: 0 }
+			];
+
+			if unsafe { libc::poll(poll_fds.as_mut_ptr(), poll_fds.len() as libc::nfds_t, -1) } < 0 {
+				let error = io::Error::last_os_error();
+				if error.kind() == io::ErrorKind::Interrupted { continue; }
+				return Err(Error::OsError(error));
+			}
+
+			if poll_fds[1].revents != 0 {
+				return Ok(());
+			}
+
+			let length = unsafe { libc::read(self.inotify_fd, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) };
+			if length < 0 {
+				return Err(Error::OsError(io::Error::last_os_error()));
+			}
+
+			let mut offset = 0;
+			while offset + size_of::<libc::inotify_event>() <= length as usize {
+				let event = unsafe { ptr::read_unaligned(buffer.as_ptr().add(offset) as *const libc::inotify_event) };
+				offset += size_of::<libc::inotify_event>() + event.len as usize;
+
+				if event.mask & libc::IN_CLOSE_NOWRITE == 0 { continue; }
+
+				if let Some(file) = self.files.iter_mut().find(|file| file.wd >= 0 && file.wd == event.wd) {
+					send(&mut file.read_message);
+					if clear_file(file.fd).is_err() {
+						send(&mut file.failed_message);
+					}
+
+					unsafe { libc::inotify_rm_watch(self.inotify_fd, file.wd) };
+					file.wd = -1;
+					remaining -= 1;
+				}
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Starts the supervisor process watching the files. The supervisor is detached from
+	/// this process so the service does not have to reap it. It terminates with the
+	/// service process. Reads of the service before the supervisor was started are
+	/// queued by inotify and are not lost.
+	pub fn supervise(mut self, service: libc::pid_t) -> Result<(), Error> {
+		let service_fd = unsafe { libc::syscall(libc::SYS_pidfd_open, service, 0) } as RawFd;
+		if service_fd < 0 {
+			return Err(Error::OsError(io::Error::last_os_error()));
+		}
+
+		debug!("Starting supervisor for read once files...");
+		let mut failed_message = prepare_message(Level::Error, &[], format_args!("Watching read once files failed."));
+
+		// The supervisor inherits the dumpable flag. It must not be dumpable. Otherwise
+		// the service could attach to it and read the secret files.
+		let _undumpable = Undumpable::new()?;
+		let child = unsafe { libc::fork() };
+		if child < 0 {
+			let error = io::Error::last_os_error();
+			unsafe { libc::close(service_fd) };
+			return Err(Error::OsError(error));
+		}
+
+		if child == 0 {
+			// Fork a second time and let the intermediate process terminate. The supervisor
+			// gets reparented to init (or the next subreaper).
+			let exit_code = match unsafe { libc::fork() } {
+				0 => if self.run(service_fd).is_ok() {
+					0
+				} else {
+					send(&mut failed_message);
+					1
+				},
+				supervisor if supervisor < 0 => 1,
+				_ => 0
+			};
+			unsafe { libc::_exit(exit_code) };
+		}
+
+		unsafe { libc::close(service_fd) };
+
+		let mut status = 0;
+		if unsafe { libc::waitpid(child, &mut status, 0) } < 0 {
+			return Err(Error::OsError(io::Error::last_os_error()));
+		}
+		if !libc::WIFEXITED(status) || libc::WEXITSTATUS(status) != 0 {
+			return Err(Error::ExecFailed(libc::WEXITSTATUS(status)));
+		}
+
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use super::super::TempDir;
+	use std::fs::{File, read_to_string, metadata};
+	use std::io::Write;
+	use std::process::Command;
+
+	/// Test that a file is cleared after it was read and that the supervisor stops if the
+	/// service terminates.
+	#[test]
+	fn read_once() {
+		let mut tmp = TempDir::new("test").unwrap();
+		let file = tmp.create_file("secret").unwrap();
+		let other = tmp.create_file("other").unwrap();
+		File::create(&file).unwrap().write_all(b"secret").unwrap();
+		File::create(&other).unwrap().write_all(b"other").unwrap();
+
+		let mut read_once = ReadOnce::new().unwrap();
+		read_once.add(&file, "provider", "secret").unwrap();
+		read_once.add(&other, "provider", "other").unwrap();
+
+		let mut service = Command::new("/bin/sh").arg("-c").arg("cat \"$0\" > /dev/null; sleep 1").arg(&file).spawn().unwrap();
+		let service_fd = unsafe { libc::syscall(libc::SYS_pidfd_open, service.id(), 0) } as RawFd;
+		assert!(service_fd >= 0);
+
+		read_once.run(service_fd).unwrap();
+		unsafe { libc::close(service_fd) };
+		service.wait().unwrap();
+
+		assert_eq!(metadata(&file).unwrap().len(), 0, "File was not cleared");
+		assert_eq!(read_to_string(&other).unwrap(), "other", "File was cleared without being read");
+	}
+}
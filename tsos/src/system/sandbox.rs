@@ -0,0 +1,324 @@
+use log::debug;
+use std::path::Path;
+use std::ffi::CString;
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::unix::io::RawFd;
+
+use super::Error;
+
+// Access rights from linux/landlock.h
+const LANDLOCK_ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const LANDLOCK_ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const LANDLOCK_ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const LANDLOCK_ACCESS_FS_READ_DIR: u64 = 1 << 3;
+const LANDLOCK_ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+const LANDLOCK_ACCESS_FS_IOCTL_DEV: u64 = 1 << 15;
+
+/// Access rights that can be granted on files. All other rights are only valid for directories.
+const LANDLOCK_ACCESS_FS_FILE: u64 = LANDLOCK_ACCESS_FS_EXECUTE | LANDLOCK_ACCESS_FS_WRITE_FILE | LANDLOCK_ACCESS_FS_READ_FILE | LANDLOCK_ACCESS_FS_TRUNCATE | LANDLOCK_ACCESS_FS_IOCTL_DEV;
+
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1 << 0;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+
+#[repr(C)]
+struct LandlockRulesetAttr {
+	handled_access_fs: u64
+}
+
+#[repr(C, packed)]
+struct LandlockPathBeneathAttr {
+	allowed_access: u64,
+	parent_fd: i32
+}
+
+// Architecture identifiers from linux/audit.h
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: Option<u32> = Some(0xC000_003E);
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: Option<u32> = Some(0xC000_00B7);
+#[cfg(target_arch = "arm")]
+const AUDIT_ARCH: Option<u32> = Some(0x4000_0028);
+#[cfg(target_arch = "riscv64")]
+const AUDIT_ARCH: Option<u32> = Some(0xC000_00F3);
+#[cfg(all(target_arch = "powerpc64", target_endian = "little"))]
+const AUDIT_ARCH: Option<u32> = Some(0xC000_0015);
+#[cfg(all(target_arch = "powerpc64", target_endian = "big"))]
+const AUDIT_ARCH: Option<u32> = Some(0x8000_0015);
+#[cfg(target_arch = "s390x")]
+const AUDIT_ARCH: Option<u32> = Some(0x8000_0016);
+/// The seccomp filter is not available on other architectures.
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64", target_arch = "arm", target_arch = "riscv64", target_arch = "powerpc64", target_arch = "s390x")))]
+const AUDIT_ARCH: Option<u32> = None;
+
+/// Offsets of the fields within struct seccomp_data
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+
+/// System calls a sandboxed process is not allowed to use. These would allow it
+/// to change the mounts within our mount namespace or to tamper with other processes.
+const DENIED_SYSCALLS: &[libc::c_long] = &[
+	libc::SYS_mount,
+	libc::SYS_umount2,
+	libc::SYS_pivot_root,
+	libc::SYS_open_tree,
+	libc::SYS_move_mount,
+	libc::SYS_fsopen,
+	libc::SYS_fsconfig,
+	libc::SYS_fsmount,
+	libc::SYS_fspick,
+	libc::SYS_mount_setattr,
+	libc::SYS_setns,
+	libc::SYS_ptrace,
+	libc::SYS_process_vm_readv,
+	libc::SYS_process_vm_writev,
+	libc::SYS_pidfd_getfd
+];
+
+/// Access a sandboxed process gets to a path.
+#[derive(Debug, Clone, Copy)]
+pub enum Access {
+	Read,
+	Write
+}
+
+/// Describes the restrictions for a process. The sandbox is prepared in the parent
+/// process and applied between fork and exec by calling apply.
+#[derive(Default)]
+pub struct Sandbox {
+	ruleset: Option<(RawFd, u64)>,
+	filter: Option<Vec<libc::sock_filter>>
+}
+
+impl Drop for Sandbox {
+	fn drop(&mut self) {
+		if let Some((ruleset_fd, _)) = self.ruleset {
+			unsafe { libc::close(ruleset_fd) };
+		}
+	}
+}
+
+impl Sandbox {
+	pub fn new() -> Self {
+		Self {
+			ruleset: None,
+			filter: None
+		}
+	}
+
+	/// Restricts the file system access of the sandboxed process to the paths
+	/// added via allow.
+	pub fn restrict_paths(&mut self) -> Result<(), Error> {
+		let abi = unsafe { libc::syscall(libc::SYS_landlock_create_ruleset, std::ptr::null::<LandlockRulesetAttr>(), 0, LANDLOCK_CREATE_RULESET_VERSION) };
+		if abi < 0 {
+			return Err(Error::OsError(io::Error::last_os_error()));
+		}
+
+		// Only handle the access rights known to the running kernel.
+		let handled_access_fs = match abi {
+			1 => (1 << 13) - 1,
+			2 => (1 << 14) - 1,
+			3 | 4 => (1 << 15) - 1,
+			_ => (1 << 16) - 1
+		};
+
+		debug!("Creating landlock ruleset for ABI version {}...", abi);
+
+		let attr = LandlockRulesetAttr { handled_access_fs };
+		let ruleset_fd = unsafe { libc::syscall(libc::SYS_landlock_create_ruleset, &attr as *const LandlockRulesetAttr, std::mem::size_of::<LandlockRulesetAttr>(), 0) };
+		if ruleset_fd < 0 {
+			Err(Error::OsError(io::Error::last_os_error()))
+		} else {
+			self.ruleset = Some((ruleset_fd as RawFd, handled_access_fs));
+			Ok(())
+		}
+	}
+
+	/// Allows access to the passed path and everything beneath it.
+	/// restrict_paths must have been called before.
+	pub fn allow(&mut self, path: &Path, access: Access) -> Result<(), Error> {
+		let (ruleset_fd, handled_access_fs) = self.ruleset.ok_or(Error::SandboxNotPrepared)?;
+
+		let c_path = CString::new(path.to_str().unwrap())?;
+		let path_fd = unsafe { libc::open(c_path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+		if path_fd < 0 {
+			return Err(Error::OsError(io::Error::last_os_error()));
+		}
+
+		let mut allowed_access = match access {
+			Access::Read => LANDLOCK_ACCESS_FS_EXECUTE | LANDLOCK_ACCESS_FS_READ_FILE | LANDLOCK_ACCESS_FS_READ_DIR,
+			Access::Write => handled_access_fs
+		} & handled_access_fs;
+
+		// Directory specific access rights are rejected for files.
+		let mut stat = MaybeUninit::<libc::stat64>::zeroed();
+		if unsafe { libc::fstat64(path_fd, stat.as_mut_ptr()) } != 0 {
+			let error = io::Error::last_os_error();
+			unsafe { libc::close(path_fd) };
+			return Err(Error::OsError(error));
+		}
+		if unsafe { stat.assume_init() }.st_mode & libc::S_IFMT != libc::S_IFDIR {
+			allowed_access &= LANDLOCK_ACCESS_FS_FILE;
+		}
+
+		debug!("Allowing {:?} access to {}...", access, path.display());
+
+		let rule = LandlockPathBeneathAttr { allowed_access, parent_fd: path_fd };
+		let result = unsafe { libc::syscall(libc::SYS_landlock_add_rule, ruleset_fd, LANDLOCK_RULE_PATH_BENEATH, &rule as *const LandlockPathBeneathAttr, 0) };
+		let error = io::Error::last_os_error();
+		unsafe { libc::close(path_fd) };
+
+		if result < 0 {
+			Err(Error::OsError(error))
+		} else {
+			Ok(())
+		}
+	}
+
+	/// Denies all system calls that can be used to change mounts or to tamper with
+	/// other processes. These calls fail with EPERM.
+	/// Fails if the filter is not available for the architecture.
+	pub fn deny_mount_and_ptrace(&mut self) -> Result<(), Error> {
+		let audit_arch = AUDIT_ARCH.ok_or_else(|| Error::Unsupported(String::from("seccomp")))?;
+		let mut filter = Vec::with_capacity(DENIED_SYSCALLS.len() * 2 + 6);
+
+		// Kill the process if the system call is made for a different architecture.
+		filter.push(bpf_stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, SECCOMP_DATA_ARCH));
+		filter.push(bpf_jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, audit_arch, 1, 0));
+		filter.push(bpf_stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_KILL_PROCESS));
+
+		filter.push(bpf_stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, SECCOMP_DATA_NR));
+
+		// Deny the x32 ABI. It uses the same architecture identifier.
+		#[cfg(target_arch = "x86_64")]
+		{
+			filter.push(bpf_jump(libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K, 0x4000_0000, 0, 1));
+			filter.push(bpf_stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ERRNO | libc::EPERM as u32));
+		}
+
+		for &syscall in DENIED_SYSCALLS {
+			filter.push(bpf_jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, syscall as u32, 0, 1));
+			filter.push(bpf_stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ERRNO | libc::EPERM as u32));
+		}
+
+		filter.push(bpf_stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ALLOW));
+
+		self.filter = Some(filter);
+		Ok(())
+	}
+
+	/// Applies the sandbox to the current process.
+	/// This function is meant to be called between fork and exec. Therefore it does
+	/// not allocate any memory.
+	pub fn apply(&self) -> io::Result<()> {
+		// Landlock and seccomp require no_new_privs for unprivileged processes.
+		if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+			return Err(io::Error::last_os_error());
+		}
+
+		if let Some((ruleset_fd, _)) = self.ruleset {
+			if unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset_fd, 0) } != 0 {
+				return Err(io::Error::last_os_error());
+			}
+		}
+
+		if let Some(ref filter) = self.filter {
+			let program = libc::sock_fprog {
+				len: filter.len() as libc::c_ushort,
+				filter: filter.as_ptr() as *mut libc::sock_filter
+			};
+
+			if unsafe { libc::prctl(libc::PR_SET_SECCOMP, libc::SECCOMP_MODE_FILTER, &program as *const libc::sock_fprog) } != 0 {
+				return Err(io::Error::last_os_error());
+			}
+		}
+
+		Ok(())
+	}
+}
+
+fn bpf_stmt(code: u32, k: u32) -> libc::sock_filter {
+	libc::sock_filter { code: code as u16, jt: 0, jf: 0, k }
+}
+
+fn bpf_jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+	libc::sock_filter { code: code as u16, jt, jf, k }
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use super::super::TempDir;
+	use std::fs::read_to_string;
+	use std::process::Command;
+	use std::os::unix::process::CommandExt;
+
+	/// Test that a sandboxed process can only write to the allowed paths.
+	#[test]
+	fn landlock() {
+		let mut tmp = TempDir::new("test").unwrap();
+		let allowed = tmp.create_file("allowed").unwrap();
+		let denied = tmp.create_file("denied").unwrap();
+
+		let mut sandbox = Sandbox::new();
+		sandbox.restrict_paths().unwrap();
+		sandbox.allow(Path::new("/usr"), Access::Read).unwrap();
+		sandbox.allow(&allowed, Access::Write).unwrap();
+
+		let status = unsafe {
+			Command::new("/bin/sh")
+				.arg("-c")
+				.arg(format!("echo allowed > {}; echo denied > {}", allowed.display(), denied.display()))
+				.pre_exec(move || sandbox.apply())
+				.status().unwrap()
+		};
+
+		assert!(!status.success(), "Writing a denied file succeeded.");
+		assert_eq!(read_to_string(&allowed).unwrap(), "allowed\n");
+		assert_eq!(read_to_string(&denied).unwrap(), "");
+	}
+
+	/// Calls mount within a child process after the sandbox was applied and returns
+	/// the errno of the call. The mount point does not exist. Therefore the call fails
+	/// with ENOENT unless it is denied.
+	fn mount_errno(sandbox: Option<Sandbox>) -> Option<i32> {
+		let result = unsafe {
+			Command::new("/usr/bin/true")
+				.pre_exec(move || {
+					if let Some(ref sandbox) = sandbox { sandbox.apply()?; }
+
+					let mount_point = b"/nonexistent/tsos\0".as_ptr() as *const libc::c_char;
+					if libc::mount(std::ptr::null(), mount_point, std::ptr::null(), libc::MS_BIND, std::ptr::null()) != 0 {
+						return Err(io::Error::last_os_error());
+					}
+
+					Ok(())
+				})
+				.status()
+		};
+
+		result.err().and_then(|v| v.raw_os_error())
+	}
+
+	/// Test that mount related system calls are denied by the seccomp filter.
+	#[test]
+	fn seccomp() {
+		assert_eq!(mount_errno(None), Some(libc::ENOENT));
+
+		let mut sandbox = Sandbox::new();
+		sandbox.deny_mount_and_ptrace().unwrap();
+
+		assert_eq!(mount_errno(Some(sandbox)), Some(libc::EPERM), "Mount was not denied by the seccomp filter.");
+	}
+
+	/// Test that allow fails if restrict_paths has not been called.
+	#[test]
+	fn allow_without_restrict_paths() {
+		let mut sandbox = Sandbox::new();
+
+		match sandbox.allow(Path::new("/usr"), Access::Read) {
+			Err(Error::SandboxNotPrepared) => (),
+			result => panic!("Unexpected result: {:?}", result)
+		}
+	}
+}
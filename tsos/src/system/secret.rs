@@ -0,0 +1,303 @@
+use log::{debug, warn};
+use serde::de::{self, Deserialize, Deserializer, Visitor};
+use std::ops::Deref;
+use std::ptr;
+use std::io::{self, Read, Write};
+use std::ffi::OsStr;
+use std::fmt;
+use std::str;
+
+/// Size of the first allocation if the size of the secret is not known in advance.
+const INITIAL_CAPACITY: usize = 4096;
+
+/// Memory for secret data. The pages are locked into memory, excluded from core dumps
+/// and are not inherited by child processes. The content is wiped before the memory
+/// is released.
+pub struct SecretBuffer {
+	data: *mut u8,
+	capacity: usize,
+	len: usize
+}
+
+/// Overwrites the memory with zeros. The volatile writes make sure the compiler does not
+/// remove the wiping because the memory is never read again.
+unsafe fn wipe(data: *mut u8, len: usize) {
+	for offset in 0..len {
+		ptr::write_volatile(data.add(offset), 0);
+	}
+}
+
+/// Allocates capacity bytes of anonymous memory and prepares it for storing secrets.
+fn allocate(capacity: usize) -> io::Result<*mut u8> {
+	let data = unsafe { libc::mmap(ptr::null_mut(), capacity, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1, 0) };
+	if data == libc::MAP_FAILED {
+		return Err(io::Error::last_os_error());
+	}
+
+	// The memory must be locked first. Otherwise it could get swapped out before
+	// anything is written into it.
+	let failed = unsafe { libc::mlock(data, capacity) } != 0 ||
+		unsafe { libc::madvise(data, capacity, libc::MADV_DONTDUMP) } != 0 ||
+		unsafe { libc::madvise(data, capacity, libc::MADV_WIPEONFORK) } != 0;
+	let result = if failed { Err(io::Error::last_os_error()) } else { Ok(data as *mut u8) };
+
+	if result.is_err() {
+		unsafe { libc::munmap(data, capacity) };
+	}
+
+	result
+}
+
+/// Wipes and releases memory returned by allocate.
+fn release(data: *mut u8, capacity: usize) {
+	unsafe {
+		wipe(data, capacity);
+		libc::munlock(data as *const libc::c_void, capacity);
+		libc::munmap(data as *mut libc::c_void, capacity);
+	}
+}
+
+impl Drop for SecretBuffer {
+	fn drop(&mut self) {
+		release(self.data, self.capacity);
+	}
+}
+
+impl Deref for SecretBuffer {
+	type Target = [u8];
+
+	fn deref(&self) -> &[u8] {
+		unsafe { std::slice::from_raw_parts(self.data, self.len) }
+	}
+}
+
+impl SecretBuffer {
+	/// Creates an empty buffer that can hold at least capacity bytes without growing.
+	pub fn with_capacity(capacity: usize) -> io::Result<Self> {
+		let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(1) as usize;
+		let capacity = capacity.max(1).div_ceil(page_size) * page_size;
+
+		Ok(Self {
+			data: allocate(capacity)?,
+			capacity,
+			len: 0
+		})
+	}
+
+	/// Reads everything from the reader into a new buffer. No copy of the data is left
+	/// behind in unprotected memory.
+	pub fn read_from<R: Read>(reader: &mut R) -> io::Result<Self> {
+		let mut buffer = Self::with_capacity(INITIAL_CAPACITY)?;
+
+		loop {
+			if buffer.len == buffer.capacity {
+				buffer.grow()?;
+			}
+
+			let free = unsafe { std::slice::from_raw_parts_mut(buffer.data.add(buffer.len), buffer.capacity - buffer.len) };
+			match reader.read(free) {
+				Ok(0) => return Ok(buffer),
+				Ok(length) => buffer.len += length,
+				Err(ref error) if error.kind() == io::ErrorKind::Interrupted => (),
+				Err(error) => return Err(error)
+			}
+		}
+	}
+
+	/// Doubles the capacity. The old memory is wiped after its content was copied.
+	fn grow(&mut self) -> io::Result<()> {
+		self.reserve(self.capacity * 2)
+	}
+
+	/// Makes sure the buffer can hold at least capacity bytes. The old memory is wiped
+	/// after its content was copied.
+	fn reserve(&mut self, capacity: usize) -> io::Result<()> {
+		if capacity <= self.capacity { return Ok(()); }
+		let capacity = capacity.max(self.capacity * 2);
+		let data = allocate(capacity)?;
+
+		unsafe { ptr::copy_nonoverlapping(self.data, data, self.len) };
+		release(self.data, self.capacity);
+
+		self.data = data;
+		self.capacity = capacity;
+
+		Ok(())
+	}
+
+	/// Returns the content as a string slice if it is valid UTF-8.
+	pub fn as_str(&self) -> io::Result<&str> {
+		str::from_utf8(self).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+	}
+}
+
+/// Appends to the buffer. Used to stage secrets that are rendered within the process.
+impl Write for SecretBuffer {
+	fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+		self.reserve(self.len + data.len())?;
+		unsafe { ptr::copy_nonoverlapping(data.as_ptr(), self.data.add(self.len), data.len()) };
+		self.len += data.len();
+
+		Ok(data.len())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+/// A string value of the configuration that may be a secret (e.g. within `set_env`).
+/// It is kept within a SecretBuffer and never shown by Debug.
+pub struct SecretString(SecretBuffer);
+
+impl SecretString {
+	pub fn new(value: &str) -> io::Result<Self> {
+		let mut buffer = SecretBuffer::with_capacity(value.len())?;
+		buffer.write_all(value.as_bytes())?;
+
+		Ok(Self(buffer))
+	}
+}
+
+impl Deref for SecretString {
+	type Target = str;
+
+	fn deref(&self) -> &str {
+		// The buffer is only filled from a str.
+		unsafe { str::from_utf8_unchecked(&self.0) }
+	}
+}
+
+impl AsRef<OsStr> for SecretString {
+	fn as_ref(&self) -> &OsStr {
+		OsStr::new(&**self)
+	}
+}
+
+impl fmt::Debug for SecretString {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str("\"***\"")
+	}
+}
+
+struct SecretStringVisitor;
+
+impl Visitor<'_> for SecretStringVisitor {
+	type Value = SecretString;
+
+	fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str("a string")
+	}
+
+	/// Unescaped strings are borrowed from the configuration buffer. They are copied
+	/// without leaving anything behind.
+	fn visit_str<E: de::Error>(self, value: &str) -> Result<SecretString, E> {
+		SecretString::new(value).map_err(E::custom)
+	}
+
+	/// Strings containing escape sequences are unescaped by the parser into an ordinary
+	/// String. It is wiped after it was copied.
+	fn visit_string<E: de::Error>(self, mut value: String) -> Result<SecretString, E> {
+		let secret = SecretString::new(&value).map_err(E::custom);
+		unsafe { wipe(value.as_mut_ptr(), value.len()) };
+
+		secret
+	}
+}
+
+impl<'de> Deserialize<'de> for SecretString {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		deserializer.deserialize_string(SecretStringVisitor)
+	}
+}
+
+/// Keeps the process from being dumped or attached to via ptrace by processes of the same
+/// user (e.g. secret providers). The previous state is restored on drop.
+pub struct Undumpable {
+	previous: libc::c_int
+}
+
+impl Drop for Undumpable {
+	fn drop(&mut self) {
+		if unsafe { libc::prctl(libc::PR_SET_DUMPABLE, self.previous as libc::c_ulong, 0, 0, 0) } != 0 {
+			warn!("Restoring the dumpable flag failed: {}", io::Error::last_os_error());
+		}
+	}
+}
+
+impl Undumpable {
+	pub fn new() -> io::Result<Self> {
+		let previous = unsafe { libc::prctl(libc::PR_GET_DUMPABLE, 0, 0, 0, 0) };
+		if previous < 0 {
+			return Err(io::Error::last_os_error());
+		}
+
+		debug!("Marking process as not dumpable...");
+
+		if unsafe { libc::prctl(libc::PR_SET_DUMPABLE, 0, 0, 0, 0) } != 0 {
+			Err(io::Error::last_os_error())
+		} else {
+			Ok(Self { previous })
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	/// Test that data larger than the initial allocation is read completely.
+	#[test]
+	fn secret_read() {
+		let data: Vec<u8> = (0..3 * INITIAL_CAPACITY + 17).map(|v| v as u8).collect();
+		let buffer = SecretBuffer::read_from(&mut data.as_slice()).unwrap();
+
+		assert_eq!(&buffer[..], &data[..]);
+		assert!(buffer.capacity >= buffer.len());
+		assert!(SecretBuffer::read_from(&mut &b"\xff\xfe"[..]).unwrap().as_str().is_err());
+		assert_eq!(SecretBuffer::read_from(&mut &b"secret"[..]).unwrap().as_str().unwrap(), "secret");
+	}
+
+	/// Test that writes grow the buffer and that secret strings are not shown by Debug.
+	#[test]
+	fn secret_write() {
+		let mut buffer = SecretBuffer::with_capacity(1).unwrap();
+		let data: Vec<u8> = (0..2 * INITIAL_CAPACITY + 5).map(|v| v as u8).collect();
+		buffer.write_all(b"x").unwrap();
+		buffer.write_all(&data).unwrap();
+
+		assert_eq!(buffer[0], b'x');
+		assert_eq!(&buffer[1..], &data[..]);
+
+		let secret = SecretString::new("password").unwrap();
+		assert_eq!(&*secret, "password");
+		assert_eq!(format!("{:?}", secret), "\"***\"");
+	}
+
+	/// Test that the buffer pages are locked and excluded from core dumps.
+	#[test]
+	fn secret_locked() {
+		let buffer = SecretBuffer::read_from(&mut &b"secret"[..]).unwrap();
+
+		let smaps = std::fs::read_to_string("/proc/self/smaps").unwrap();
+		let start = format!("{:x}-", buffer.data as usize);
+		// VmFlags is the last entry of every mapping.
+		let flags = smaps.lines().skip_while(|line| !line.starts_with(&start)).find(|line| line.starts_with("VmFlags:")).unwrap();
+		assert!(flags.contains(" lo"));
+		assert!(flags.contains(" dd"));
+		assert!(flags.contains(" wf"));
+	}
+
+	/// Test that the process is not dumpable while the guard exists.
+	#[test]
+	fn undumpable() {
+		let previous = unsafe { libc::prctl(libc::PR_GET_DUMPABLE, 0, 0, 0, 0) };
+
+		{
+			let _guard = Undumpable::new().unwrap();
+			assert_eq!(unsafe { libc::prctl(libc::PR_GET_DUMPABLE, 0, 0, 0, 0) }, 0);
+		}
+
+		assert_eq!(unsafe { libc::prctl(libc::PR_GET_DUMPABLE, 0, 0, 0, 0) }, previous);
+	}
+}
@@ -0,0 +1,150 @@
+use std::io::{self, Read};
+use std::fmt::Write;
+
+const K: [u32; 64] = [
+	0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+	0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+	0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+	0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+	0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+	0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+	0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+	0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2
+];
+
+const H: [u32; 8] = [ 0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19 ];
+
+/// SHA-256 as defined in FIPS 180-4. Used to identify secret providers.
+pub struct Sha256 {
+	state: [u32; 8],
+	block: [u8; 64],
+	block_len: usize,
+	total_len: u64
+}
+
+impl Default for Sha256 {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl Sha256 {
+	pub fn new() -> Self {
+		Self {
+			state: H,
+			block: [0; 64],
+			block_len: 0,
+			total_len: 0
+		}
+	}
+
+	fn compress(&mut self) {
+		let mut w = [0u32; 64];
+		for (i, chunk) in self.block.chunks_exact(4).enumerate() {
+			w[i] = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+		}
+		for i in 16..64 {
+			let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+			let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+			w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+		}
+
+		let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+		for i in 0..64 {
+			let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+			let ch = (e & f) ^ (!e & g);
+			let t1 = h.wrapping_add(s1).wrapping_add(ch).wrapping_add(K[i]).wrapping_add(w[i]);
+			let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+			let maj = (a & b) ^ (a & c) ^ (b & c);
+			let t2 = s0.wrapping_add(maj);
+
+			h = g;
+			g = f;
+			f = e;
+			e = d.wrapping_add(t1);
+			d = c;
+			c = b;
+			b = a;
+			a = t1.wrapping_add(t2);
+		}
+
+		for (state, value) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h].iter()) {
+			*state = state.wrapping_add(*value);
+		}
+	}
+
+	pub fn update(&mut self, mut data: &[u8]) {
+		self.total_len += data.len() as u64;
+
+		while !data.is_empty() {
+			let length = (64 - self.block_len).min(data.len());
+			self.block[self.block_len..self.block_len + length].copy_from_slice(&data[..length]);
+			self.block_len += length;
+			data = &data[length..];
+
+			if self.block_len == 64 {
+				self.compress();
+				self.block_len = 0;
+			}
+		}
+	}
+
+	pub fn finish(mut self) -> [u8; 32] {
+		let bit_len = self.total_len.wrapping_mul(8);
+
+		// Append the 1 bit and pad with zeros until 8 bytes are left for the length.
+		self.block[self.block_len] = 0x80;
+		self.block_len += 1;
+		if self.block_len > 56 {
+			self.block[self.block_len..].fill(0);
+			self.compress();
+			self.block_len = 0;
+		}
+		self.block[self.block_len..56].fill(0);
+		self.block[56..].copy_from_slice(&bit_len.to_be_bytes());
+		self.compress();
+
+		let mut digest = [0u8; 32];
+		for (chunk, value) in digest.chunks_exact_mut(4).zip(self.state.iter()) {
+			chunk.copy_from_slice(&value.to_be_bytes());
+		}
+
+		digest
+	}
+}
+
+/// Hashes everything the reader returns and returns the digest as a lower case hex string.
+pub fn sha256_hex<R: Read>(reader: &mut R) -> io::Result<String> {
+	let mut hasher = Sha256::new();
+	let mut buffer = [0u8; 8192];
+
+	loop {
+		match reader.read(&mut buffer) {
+			Ok(0) => break,
+			Ok(length) => hasher.update(&buffer[..length]),
+			Err(ref error) if error.kind() == io::ErrorKind::Interrupted => (),
+			Err(error) => return Err(error)
+		}
+	}
+
+	Ok(hasher.finish().iter().fold(String::with_capacity(64), |mut hex, byte| {
+		let _ = write!(hex, "{:02x}", byte);
+		hex
+	}))
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	/// Test the digest against the test vectors of FIPS 180-4.
+	#[test]
+	fn test_vectors() {
+		assert_eq!(sha256_hex(&mut &b""[..]).unwrap(), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+		assert_eq!(sha256_hex(&mut &b"abc"[..]).unwrap(), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+		assert_eq!(sha256_hex(&mut &b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"[..]).unwrap(), "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1");
+
+		let million = vec![b'a'; 1_000_000];
+		assert_eq!(sha256_hex(&mut million.as_slice()).unwrap(), "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0");
+	}
+}
@@ -1,4 +1,3 @@
-use libc;
 use log::{debug, warn};
 use std::path::{Path, PathBuf};
 use std::ffi::{CString};
@@ -1,3 +1,5 @@
+#![allow(clippy::from_str_radix_10)]
+
 use std::io::Write;
 use std::path::PathBuf;
 use std::fs::File;
@@ -14,6 +16,11 @@ const BIN_CAT: &str = "/usr/bin/cat";
 const BIN_ID: &str = "/usr/bin/id";
 const BIN_SLEEP: &str = "/usr/bin/sleep";
 const BIN_MOUNT: &str = "/usr/bin/mount";
+const BIN_ECHO: &str = "/usr/bin/echo";
+const BIN_ENV: &str = "/usr/bin/env";
+const BIN_PWD: &str = "/usr/bin/pwd";
+const BIN_GREP: &str = "/usr/bin/grep";
+const BIN_SH: &str = "/bin/sh";
 
 const TEST_USER: &str = "nobody";
 const TEST_GROUP: &str = "nobody";
@@ -130,6 +137,87 @@ fn toml_uid_gid() {
 	assert_eq!(u32::from_str_radix(String::from_utf8_lossy(&output.stdout).trim(), 10).unwrap(), resolve_gid(TEST_GROUP));
 }
 
+/// Uses `id -G` to get the supplementary groups of a user as a sorted list.
+fn resolve_groups(user_name: &str) -> Vec<u32> {
+	let output = Command::new(BIN_ID)
+		.arg("-G")
+		.arg(user_name)
+		.output().unwrap();
+
+	to_group_list(&output.stdout)
+}
+
+/// Converts the output of `id -G` into a sorted list of group ids.
+fn to_group_list(output: &[u8]) -> Vec<u32> {
+	let mut groups: Vec<u32> = String::from_utf8_lossy(output).split_whitespace().map(|v| v.parse::<u32>().unwrap()).collect();
+	groups.sort_unstable();
+	groups.dedup();
+
+	groups
+}
+
+/// Test that the process gets the supplementary groups of the configured user by default.
+#[test]
+fn toml_init_groups() {
+	let tmp = TempDir::default();
+
+	let toml_file = to_file(&tmp, "groups.toml", &format!(r#"
+		exec = "{bin}"
+		uid = "{username}"
+
+		[secrets]
+	"#, bin = BIN_ID, username = TEST_USER));
+
+	let output = Command::new("cargo").args(CARGO_ARGS)
+		.arg(&toml_file)
+		.arg("-G")
+		.output().unwrap();
+	assert_eq!(to_group_list(&output.stdout), resolve_groups(TEST_USER));
+}
+
+/// Test that the supplementary groups of root are not inherited if init_groups is disabled.
+#[test]
+fn toml_no_init_groups() {
+	let tmp = TempDir::default();
+
+	let toml_file = to_file(&tmp, "groups.toml", &format!(r#"
+		exec = "{bin}"
+		uid = "{username}"
+		init_groups = false
+
+		[secrets]
+	"#, bin = BIN_ID, username = TEST_USER));
+
+	let output = Command::new("cargo").args(CARGO_ARGS)
+		.arg(&toml_file)
+		.arg("-G")
+		.output().unwrap();
+	assert_eq!(to_group_list(&output.stdout), vec![ resolve_gid(TEST_USER) ]);
+}
+
+/// Test that an explicit list of supplementary groups is used.
+#[test]
+fn toml_groups() {
+	let tmp = TempDir::default();
+
+	let toml_file = to_file(&tmp, "groups.toml", &format!(r#"
+		exec = "{bin}"
+		uid = "{username}"
+		groups = [ "root", 4711 ]
+
+		[secrets]
+	"#, bin = BIN_ID, username = TEST_USER));
+
+	let output = Command::new("cargo").args(CARGO_ARGS)
+		.arg(&toml_file)
+		.arg("-G")
+		.output().unwrap();
+
+	let mut reference = vec![ 0, 4711, resolve_gid(TEST_USER) ];
+	reference.sort_unstable();
+	assert_eq!(to_group_list(&output.stdout), reference);
+}
+
 /// Test that the local search order of the providers is correct.
 /// The local search order is the order defined by the search_path directive within
 /// the TOML file.
@@ -400,3 +488,906 @@ fn missing_source() {
 	
 	assert!(!output.status.success());
 }
+
+/// Verify that the fixed arguments from the configuration are passed before
+/// the arguments from the command line.
+#[test]
+fn toml_args() {
+	let tmp = TempDir::default();
+
+	let toml_file = to_file(&tmp, "test.toml", &format!(r#"
+		exec = "{bin}"
+		args = [ "first", "second" ]
+
+		[secrets]
+	"#, bin = BIN_ECHO));
+
+	let output = Command::new("cargo").args(CARGO_ARGS)
+		.arg(toml_file)
+		.arg("third")
+		.output().unwrap();
+
+	assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "first second third");
+}
+
+/// Verify that the working directory is changed before executing the program.
+#[test]
+fn toml_chdir() {
+	let tmp = TempDir::default();
+
+	let toml_file = to_file(&tmp, "test.toml", &format!(r#"
+		exec = "{bin}"
+		chdir = "/tmp"
+
+		[secrets]
+	"#, bin = BIN_PWD));
+
+	let output = Command::new("cargo").args(CARGO_ARGS)
+		.arg(toml_file)
+		.output().unwrap();
+
+	assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "/tmp");
+}
+
+/// Verify that clear_env removes all environment variables and set_env adds
+/// the configured ones.
+#[test]
+fn toml_clear_env() {
+	let tmp = TempDir::default();
+
+	let toml_file = to_file(&tmp, "test.toml", &format!(r#"
+		exec = "{bin}"
+		clear_env = true
+
+		[set_env]
+		TSOS_TEST = "value"
+
+		[secrets]
+	"#, bin = BIN_ENV));
+
+	let output = Command::new("cargo").args(CARGO_ARGS)
+		.arg(toml_file)
+		.env("TSOS_TEST_LEAK", "leak")
+		.output().unwrap();
+
+	assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "TSOS_TEST=value");
+}
+
+/// Verify that only the variables from env_allow are passed through and that
+/// variables from env_deny are removed.
+#[test]
+fn toml_env_allow_deny() {
+	let tmp = TempDir::default();
+
+	// Only allowed variables are passed through
+	{
+		let toml_file = to_file(&tmp, "test.toml", &format!(r#"
+			exec = "{bin}"
+			env_allow = [ "TSOS_TEST_A" ]
+
+			[secrets]
+		"#, bin = BIN_ENV));
+
+		let output = Command::new("cargo").args(CARGO_ARGS)
+			.arg(toml_file)
+			.env("TSOS_TEST_A", "a")
+			.env("TSOS_TEST_B", "b")
+			.output().unwrap();
+
+		assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "TSOS_TEST_A=a");
+	}
+
+	// Denied variables are removed
+	{
+		let toml_file = to_file(&tmp, "test.toml", &format!(r#"
+			exec = "{bin}"
+			env_deny = [ "TSOS_TEST_A" ]
+
+			[secrets]
+		"#, bin = BIN_ENV));
+
+		let output = Command::new("cargo").args(CARGO_ARGS)
+			.arg(toml_file)
+			.env("TSOS_TEST_A", "a")
+			.env("TSOS_TEST_B", "b")
+			.output().unwrap();
+
+		let stdout = String::from_utf8_lossy(&output.stdout);
+		assert!(!stdout.lines().any(|l| l == "TSOS_TEST_A=a"), "Denied variable was passed through.");
+		assert!(stdout.lines().any(|l| l == "TSOS_TEST_B=b"), "Variable missing from environment.");
+	}
+}
+
+/// Verify that the configured capabilities are raised into the ambient set and
+/// survive the switch of the user id.
+#[test]
+fn toml_ambient_capabilities() {
+	let tmp = TempDir::default();
+
+	let toml_file = to_file(&tmp, "test.toml", &format!(r#"
+		exec = "{bin}"
+		args = [ "^Cap\\(Amb\\|Eff\\)", "/proc/self/status" ]
+		uid = "{username}"
+		ambient_capabilities = [ "CAP_NET_BIND_SERVICE" ]
+
+		[secrets]
+	"#, bin = BIN_GREP, username = TEST_USER));
+
+	let output = Command::new("cargo").args(CARGO_ARGS)
+		.arg(toml_file)
+		.output().unwrap();
+
+	assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "CapEff:\t0000000000000400\nCapAmb:\t0000000000000400");
+}
+
+/// Verify that the no_new_privs flag is set for the executed program.
+#[test]
+fn toml_no_new_privs() {
+	let tmp = TempDir::default();
+
+	let toml_file = to_file(&tmp, "test.toml", &format!(r#"
+		exec = "{bin}"
+		args = [ "^NoNewPrivs", "/proc/self/status" ]
+		no_new_privs = true
+
+		[secrets]
+	"#, bin = BIN_GREP));
+
+	let output = Command::new("cargo").args(CARGO_ARGS)
+		.arg(toml_file)
+		.output().unwrap();
+
+	assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "NoNewPrivs:\t1");
+}
+
+/// Verify that a program executed as root does not get any capabilities if the
+/// securebits are locked.
+#[test]
+fn toml_lock_securebits() {
+	let tmp = TempDir::default();
+
+	let toml_file = to_file(&tmp, "test.toml", &format!(r#"
+		exec = "{bin}"
+		args = [ "^CapEff", "/proc/self/status" ]
+		lock_securebits = true
+
+		[secrets]
+	"#, bin = BIN_GREP));
+
+	let output = Command::new("cargo").args(CARGO_ARGS)
+		.arg(toml_file)
+		.output().unwrap();
+
+	assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "CapEff:\t0000000000000000");
+}
+
+/// Verify that a provider works within the landlock sandbox if all necessary paths are allowed.
+#[test]
+fn provider_landlock() {
+	let tmp = TempDir::default();
+
+	let source = to_file(&tmp, "source.conf", "s1");
+
+	let toml_file = to_file(&tmp, "test.toml", &format!(r#"
+		exec = "{bin}"
+		search_path = [ "{path}/sandbox" ]
+		provider_landlock = true
+		provider_read = [ "/usr" ]
+
+		[secrets]
+		provider = [ "{source}" ]
+	"#, bin = BIN_CAT, path = PROV_PATH, source = source.to_string_lossy()));
+
+	let output = Command::new("cargo").args(CARGO_ARGS)
+		.arg(toml_file)
+		.arg(source)
+		.output().unwrap();
+
+	assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), format!("s1:{}/sandbox/provider", PROV_PATH));
+}
+
+/// Verify that a provider within the landlock sandbox can not modify the template.
+#[test]
+fn provider_landlock_denied() {
+	let tmp = TempDir::default();
+
+	let source = to_file(&tmp, "source.conf", "s1");
+
+	let toml_file = to_file(&tmp, "test.toml", &format!(r#"
+		exec = "{bin}"
+		search_path = [ "{path}/sandbox" ]
+		provider_landlock = true
+		provider_read = [ "/usr" ]
+
+		[secrets]
+		provider_write = [ "{source}" ]
+	"#, bin = BIN_CAT, path = PROV_PATH, source = source.to_string_lossy()));
+
+	let output = Command::new("cargo").args(CARGO_ARGS)
+		.arg(toml_file)
+		.arg(&source)
+		.output().unwrap();
+
+	assert!(!output.status.success(), "Provider was able to write to the template.");
+	assert_eq!(std::fs::read_to_string(&source).unwrap(), "s1");
+}
+
+/// Verify that a provider can not mount anything if the seccomp filter is enabled.
+#[test]
+fn provider_seccomp() {
+	let tmp = TempDir::default();
+
+	let source = to_file(&tmp, "source.conf", "s1");
+
+	// Without the filter mounting is possible
+	{
+		let toml_file = to_file(&tmp, "test.toml", &format!(r#"
+			exec = "{bin}"
+			search_path = [ "{path}/sandbox" ]
+
+			[secrets]
+			provider_mount = [ "{source}" ]
+		"#, bin = BIN_CAT, path = PROV_PATH, source = source.to_string_lossy()));
+
+		let output = Command::new("cargo").args(CARGO_ARGS)
+			.arg(toml_file)
+			.arg(&source)
+			.output().unwrap();
+
+		assert!(output.status.success(), "Provider was not able to mount without the seccomp filter.");
+	}
+
+	// With the filter mounting fails
+	{
+		let toml_file = to_file(&tmp, "test.toml", &format!(r#"
+			exec = "{bin}"
+			search_path = [ "{path}/sandbox" ]
+			provider_seccomp = true
+
+			[secrets]
+			provider_mount = [ "{source}" ]
+		"#, bin = BIN_CAT, path = PROV_PATH, source = source.to_string_lossy()));
+
+		let output = Command::new("cargo").args(CARGO_ARGS)
+			.arg(toml_file)
+			.arg(&source)
+			.output().unwrap();
+
+		assert!(!output.status.success(), "Provider was able to mount with the seccomp filter.");
+	}
+}
+
+/// Verify that the output of the providers is overlayed over the source files if the
+/// detached ramfs is used.
+#[test]
+fn detached_mount() {
+	let tmp = TempDir::default();
+
+	let source1 = to_file(&tmp, "source1.conf", "s1");
+	let source2 = to_file(&tmp, "source2.conf", "s2");
+
+	let toml_file = to_file(&tmp, "test.toml", &format!(r#"
+		exec = "{bin}"
+		search_path = [ "{path}" ]
+		detached_mount = true
+
+		[secrets]
+		provider = [ "{source1}", "{source2}" ]
+	"#, bin = BIN_CAT, path = PROV_PATH, source1 = source1.to_string_lossy(), source2 = source2.to_string_lossy()));
+
+	let output = Command::new("cargo").args(CARGO_ARGS)
+		.arg(toml_file)
+		.arg(source1)
+		.arg(source2)
+		.output().unwrap();
+
+	assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), format!("s1:{path}/provider\ns2:{path}/provider", path = PROV_PATH));
+}
+
+/// Verify that a provider within the landlock sandbox can write to a file of the detached ramfs.
+#[test]
+fn detached_mount_landlock() {
+	let tmp = TempDir::default();
+
+	let source = to_file(&tmp, "source.conf", "s1");
+
+	let toml_file = to_file(&tmp, "test.toml", &format!(r#"
+		exec = "{bin}"
+		search_path = [ "{path}/sandbox" ]
+		detached_mount = true
+		provider_landlock = true
+		provider_read = [ "/usr" ]
+
+		[secrets]
+		provider = [ "{source}" ]
+	"#, bin = BIN_CAT, path = PROV_PATH, source = source.to_string_lossy()));
+
+	let output = Command::new("cargo").args(CARGO_ARGS)
+		.arg(toml_file)
+		.arg(source)
+		.output().unwrap();
+
+	assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), format!("s1:{}/sandbox/provider", PROV_PATH));
+}
+
+/// Verify that symbolic links to templates are followed by default and rejected
+/// if template_symlinks is set to reject.
+#[test]
+fn template_symlinks() {
+	let tmp = TempDir::default();
+
+	let source = to_file(&tmp, "source.conf", "s1");
+	let mut link = PathBuf::from(tmp.as_ref());
+	link.push("link.conf");
+	std::os::unix::fs::symlink(&source, &link).unwrap();
+
+	// Symbolic links are followed by default
+	{
+		let toml_file = to_file(&tmp, "test.toml", &format!(r#"
+			exec = "{bin}"
+			search_path = [ "{path}" ]
+
+			[secrets]
+			provider = [ "{link}" ]
+		"#, bin = BIN_CAT, path = PROV_PATH, link = link.to_string_lossy()));
+
+		let output = Command::new("cargo").args(CARGO_ARGS)
+			.arg(toml_file)
+			.arg(&source)
+			.output().unwrap();
+
+		assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), format!("s1:{}/provider", PROV_PATH));
+	}
+
+	// Symbolic links are rejected
+	{
+		let toml_file = to_file(&tmp, "test.toml", &format!(r#"
+			exec = "{bin}"
+			search_path = [ "{path}" ]
+			template_symlinks = "reject"
+
+			[secrets]
+			provider = [ "{link}" ]
+		"#, bin = BIN_CAT, path = PROV_PATH, link = link.to_string_lossy()));
+
+		let output = Command::new("cargo").args(CARGO_ARGS)
+			.arg(toml_file)
+			.arg(&source)
+			.output().unwrap();
+
+		assert!(!output.status.success(), "Symbolic link was not rejected.");
+	}
+}
+
+/// Verify that the content of a read once file is removed after the service has read it.
+/// Files that are not listed in read_once stay readable.
+#[test]
+fn read_once() {
+	let tmp = TempDir::default();
+
+	let source1 = to_file(&tmp, "source1.conf", "s1");
+	let source2 = to_file(&tmp, "source2.conf", "s2");
+
+	for detached in [ false, true ] {
+		let toml_file = to_file(&tmp, "test.toml", &format!(r#"
+			exec = "{bin}"
+			search_path = [ "{path}" ]
+			detached_mount = {detached}
+			read_once = [ "{source1}" ]
+			args = [ "-c", "cat {source1} {source2}; sleep 1; cat {source1} {source2}" ]
+
+			[secrets]
+			provider = [ "{source1}", "{source2}" ]
+		"#, bin = BIN_SH, path = PROV_PATH, detached = detached, source1 = source1.to_string_lossy(), source2 = source2.to_string_lossy()));
+
+		// The supervisor logs the cleared file with the provider and the template.
+		let output = Command::new("cargo").args(CARGO_ARGS)
+			.arg(toml_file)
+			.env("TSOS_LOG_TARGET", "json")
+			.env("TSOS_LOG", "warn,tsos::system::readonce=info")
+			.output().unwrap();
+
+		assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), format!("s1:{path}/provider\ns2:{path}/provider\ns2:{path}/provider", path = PROV_PATH));
+
+		let stderr = String::from_utf8_lossy(&output.stderr);
+		let line = stderr.lines().find(|v| v.contains("was read. Removing its content.")).unwrap_or_else(|| panic!("Clear event missing: {}", stderr));
+		assert!(line.contains(r#""level":"INFO""#), "Clear event not logged as JSON: {}", line);
+		assert!(line.contains(r#""TSOS_PROVIDER":"provider""#), "Provider missing: {}", line);
+		assert!(line.contains(&format!(r#""TSOS_TEMPLATE":"{}""#, source1.to_string_lossy())), "Template missing: {}", line);
+	}
+}
+
+/// Verify that the audit events are appended to the audit file as JSON lines.
+#[test]
+fn audit_file() {
+	let tmp = TempDir::default();
+
+	let source = to_file(&tmp, "source.conf", "s1");
+	let mut audit_file = PathBuf::from(tmp.as_ref());
+	audit_file.push("audit.jsonl");
+
+	let toml_file = to_file(&tmp, "test.toml", &format!(r#"
+		exec = "{bin}"
+		search_path = [ "{path}" ]
+		audit_file = "{audit}"
+
+		[secrets]
+		provider = [ "{source}" ]
+	"#, bin = BIN_CAT, path = PROV_PATH, audit = audit_file.to_string_lossy(), source = source.to_string_lossy()));
+
+	let output = Command::new("cargo").args(CARGO_ARGS)
+		.arg(&toml_file)
+		.arg(&source)
+		.output().unwrap();
+	assert!(output.status.success());
+
+	// Use sha256sum to get the reference value for the hash of the provider.
+	let sha256 = Command::new("sha256sum").arg(format!("{}/provider", PROV_PATH)).output().unwrap();
+	let sha256 = String::from_utf8_lossy(&sha256.stdout).split_whitespace().next().unwrap().to_string();
+
+	let audit = std::fs::read_to_string(&audit_file).unwrap();
+	let lines: Vec<&str> = audit.lines().collect();
+	assert_eq!(lines.len(), 2);
+
+	assert!(lines[0].contains(r#""tsos_event":"provision""#));
+	assert!(lines[0].contains(r#""tsos_outcome":"success""#));
+	assert!(lines[0].contains(&format!(r#""tsos_template":"{}""#, source.to_string_lossy())));
+	assert!(lines[0].contains(&format!(r#""tsos_provider_sha256":"{}""#, sha256)));
+	assert!(lines[0].contains(&format!(r#""tsos_config":"{}""#, toml_file.to_string_lossy())));
+
+	assert!(lines[1].contains(r#""tsos_event":"exec""#));
+	assert!(lines[1].contains(r#""tsos_outcome":"started""#));
+	assert!(lines[1].contains(&format!(r#""tsos_exec":"{}""#, BIN_CAT)));
+}
+
+/// Verify that the audit events are recorded with the default log level and without
+/// an audit file.
+#[test]
+fn audit_default_level() {
+	let tmp = TempDir::default();
+
+	let source = to_file(&tmp, "source.conf", "s1");
+	let toml_file = to_file(&tmp, "test.toml", &format!(r#"
+		exec = "{bin}"
+		search_path = [ "{path}" ]
+
+		[secrets]
+		provider = [ "{source}" ]
+	"#, bin = BIN_CAT, path = PROV_PATH, source = source.to_string_lossy()));
+
+	let output = Command::new("cargo").args(CARGO_ARGS)
+		.arg(&toml_file)
+		.arg(&source)
+		.env_remove("TSOS_LOG")
+		.env_remove("TSOS_LOG_TARGET")
+		.env_remove("JOURNAL_STREAM")
+		.output().unwrap();
+	assert!(output.status.success());
+	assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), format!("s1:{}/provider", PROV_PATH));
+
+	let stderr = String::from_utf8_lossy(&output.stderr);
+	assert!(stderr.contains(r#""tsos_event":"provision""#), "provision event missing: {}", stderr);
+	assert!(stderr.contains(r#""tsos_provider_sha256":""#), "hash of the provider missing: {}", stderr);
+	assert!(stderr.contains(r#""tsos_event":"exec""#), "exec event missing: {}", stderr);
+}
+
+/// Verify that a provider is only executed if it matches the configured SHA-256 hash.
+/// A pinned provider is executed via its file descriptor.
+#[test]
+fn provider_sha256() {
+	let tmp = TempDir::default();
+
+	let source = to_file(&tmp, "source.conf", "s1");
+
+	// Use sha256sum to get the reference value for the hash of the provider.
+	let sha256 = Command::new("sha256sum").arg(format!("{}/provider", PROV_PATH)).output().unwrap();
+	let sha256 = String::from_utf8_lossy(&sha256.stdout).split_whitespace().next().unwrap().to_string();
+
+	for (hash, matches) in [ (sha256.as_str(), true), ("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855", false) ] {
+		let toml_file = to_file(&tmp, "test.toml", &format!(r#"
+			exec = "{bin}"
+			search_path = [ "{path}" ]
+
+			[secrets]
+			provider = [ "{source}" ]
+
+			[provider_sha256]
+			provider = "{hash}"
+		"#, bin = BIN_CAT, path = PROV_PATH, source = source.to_string_lossy(), hash = hash));
+
+		let output = Command::new("cargo").args(CARGO_ARGS)
+			.arg(toml_file)
+			.arg(&source)
+			.output().unwrap();
+
+		assert_eq!(output.status.success(), matches);
+		if matches {
+			assert!(String::from_utf8_lossy(&output.stdout).starts_with("s1:/proc/self/fd/"), "Provider was not executed via its file descriptor.");
+		} else {
+			assert!(!String::from_utf8_lossy(&output.stdout).contains("s1:"), "Provider was executed although its hash does not match.");
+		}
+	}
+}
+
+/// Verify that a configuration file that is writable by other users or is a symbolic
+/// link is refused if TSoS runs with elevated privileges.
+#[test]
+fn config_permissions() {
+	use std::os::unix::fs::PermissionsExt;
+
+	let tmp = TempDir::default();
+
+	let toml_file = to_file(&tmp, "test.toml", &format!(r#"
+		exec = "{bin}"
+
+		[secrets]
+	"#, bin = BIN_ECHO));
+	let mut link = PathBuf::from(tmp.as_ref());
+	link.push("link.toml");
+	std::os::unix::fs::symlink(&toml_file, &link).unwrap();
+
+	for (mode, config, accepted) in [ (0o644, &toml_file, true), (0o664, &toml_file, false), (0o646, &toml_file, false), (0o644, &link, false) ] {
+		std::fs::set_permissions(&toml_file, std::fs::Permissions::from_mode(mode)).unwrap();
+
+		let output = Command::new("cargo").args(CARGO_ARGS)
+			.arg(config)
+			.arg("started")
+			.output().unwrap();
+
+		assert_eq!(output.status.success(), accepted, "Unexpected result for mode {:o} of {}.", mode, config.display());
+	}
+}
+
+/// Verify that the library prepares a command that sees the provisioned secrets without
+/// leaking the mounts into the calling process.
+#[test]
+fn library_session() {
+	use tsos::Session;
+	use tsos::config::Config;
+
+	let tmp = TempDir::default();
+
+	let source = to_file(&tmp, "source1.conf", "s1");
+
+	let toml_file = to_file(&tmp, "test.toml", &format!(r#"
+		exec = "{bin}"
+		search_path = [ "{path}" ]
+
+		[secrets]
+		provider = [ "{source}" ]
+	"#, bin = BIN_CAT, path = PROV_PATH, source = source.to_string_lossy()));
+
+	// The session moves the calling thread into a new mount namespace.
+	let thread_source = source.clone();
+	let output = std::thread::spawn(move || {
+		let mut session = Session::from_config(Config::new(&toml_file, None).unwrap()).unwrap();
+		let prepared = session.prepare().unwrap();
+		let output = session.command().unwrap().arg(thread_source).output().unwrap();
+		drop(prepared);
+
+		output
+	}).join().unwrap();
+
+	assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), format!("s1:{path}/provider", path = PROV_PATH));
+	assert_eq!(std::fs::read_to_string(&source).unwrap(), "s1", "Bind mount leaked");
+}
+
+/// Verify that an in-process provider is used instead of the search path and that its
+/// failure aborts the preparation.
+#[test]
+fn library_provider() {
+	use std::io::{Read, Write};
+	use tsos::{Session, SecretProvider, ProviderContext};
+	use tsos::config::Config;
+
+	struct Upper;
+
+	impl SecretProvider for Upper {
+		fn render(&self, template: &mut dyn Read, out: &mut dyn Write, ctx: &ProviderContext) -> Result<(), Box<dyn std::error::Error>> {
+			let mut content = String::new();
+			template.read_to_string(&mut content)?;
+			if content == "fail" { return Err("refused".into()); }
+			write!(out, "{}:{}", content.to_uppercase(), ctx.name())?;
+
+			Ok(())
+		}
+	}
+
+	let tmp = TempDir::default();
+
+	let source = to_file(&tmp, "source1.conf", "s1");
+	let failing = to_file(&tmp, "source2.conf", "fail");
+
+	let toml_file = to_file(&tmp, "test.toml", &format!(r#"
+		exec = "{bin}"
+		search_path = [ "{path}" ]
+
+		[secrets]
+		provider = [ "{source}" ]
+	"#, bin = BIN_CAT, path = PROV_PATH, source = source.to_string_lossy()));
+	let failing_toml_file = to_file(&tmp, "failing.toml", &format!(r#"
+		exec = "{bin}"
+
+		[secrets]
+		provider = [ "{source}" ]
+	"#, bin = BIN_CAT, source = failing.to_string_lossy()));
+
+	let thread_source = source.clone();
+	let (output, error) = std::thread::spawn(move || {
+		let mut session = Session::from_config(Config::new(&toml_file, None).unwrap()).unwrap();
+		session.register_provider("provider", Upper);
+		let prepared = session.prepare().unwrap();
+		let output = session.command().unwrap().arg(thread_source).output().unwrap();
+		drop(prepared);
+
+		let mut session = Session::from_config(Config::new(&failing_toml_file, None).unwrap()).unwrap();
+		session.register_provider("provider", Upper);
+		let error = session.prepare().err().unwrap().to_string();
+
+		(output, error)
+	}).join().unwrap();
+
+	assert_eq!(String::from_utf8_lossy(&output.stdout), "S1:provider");
+	assert_eq!(error, "Provider provider failed: refused");
+}
+
+/// Verify that the provider gets its context via environment variables and that the
+/// environment of TSoS is only passed on if it is allowed.
+#[test]
+fn provider_context() {
+	let tmp = TempDir::default();
+
+	let source = to_file(&tmp, "source1.conf", "s1");
+
+	let toml_file = to_file(&tmp, "test.toml", &format!(r#"
+		exec = "{bin}"
+		search_path = [ "{path}/context" ]
+		provider_env_allow = [ "TEST_ALLOWED" ]
+
+		[secrets]
+		provider = [ "{source}" ]
+	"#, bin = BIN_CAT, path = PROV_PATH, source = source.to_string_lossy()));
+
+	let output = Command::new("cargo").args(CARGO_ARGS)
+		.arg(toml_file)
+		.arg(&source)
+		.env("TEST_ALLOWED", "allowed")
+		.env("TEST_DENIED", "denied")
+		.output().unwrap();
+
+	assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), format!("provider {source} {bin} {uid} {version} allowed-",
+		source = source.display(), bin = BIN_CAT, uid = String::from_utf8_lossy(&Command::new(BIN_ID).arg("-u").output().unwrap().stdout).trim(), version = env!("CARGO_PKG_VERSION")));
+}
+
+/// Verify that two providers can share one executable with different arguments,
+/// environment and protocols.
+#[test]
+fn provider_settings() {
+	let tmp = TempDir::default();
+
+	let source1 = to_file(&tmp, "source1.conf", "s1");
+	let source2 = to_file(&tmp, "source2.conf", "s2");
+
+	let toml_file = to_file(&tmp, "test.toml", &format!(r#"
+		exec = "{bin}"
+		search_path = [ "{path}/settings" ]
+
+		[secrets]
+		files = [ "{source1}" ]
+		stdio = [ "{source2}" ]
+
+		[providers.files]
+		executable = "shared"
+		args = [ "a", "b" ]
+		env = {{ PROVIDER_VALUE = "v1" }}
+
+		[providers.stdio]
+		executable = "shared"
+		args = [ "c" ]
+		env = {{ PROVIDER_VALUE = "v2" }}
+		protocol = "stdio"
+	"#, bin = BIN_CAT, path = PROV_PATH, source1 = source1.to_string_lossy(), source2 = source2.to_string_lossy()));
+
+	let output = Command::new("cargo").args(CARGO_ARGS)
+		.arg(toml_file)
+		.arg(source1)
+		.arg(source2)
+		.output().unwrap();
+
+	assert_eq!(String::from_utf8_lossy(&output.stdout), "s1:a b:v1\ns2:c:v2\n");
+}
+
+/// Verify that an executable containing a path is rejected instead of being looked up
+/// by its file name.
+#[test]
+fn provider_executable_path() {
+	let tmp = TempDir::default();
+
+	let source = to_file(&tmp, "source.conf", "s1");
+	let toml_file = to_file(&tmp, "test.toml", &format!(r#"
+		exec = "{bin}"
+		search_path = [ "{path}/settings" ]
+
+		[secrets]
+		files = [ "{source}" ]
+
+		[providers.files]
+		executable = "{path}/settings/shared"
+	"#, bin = BIN_CAT, path = PROV_PATH, source = source.to_string_lossy()));
+
+	let output = Command::new("cargo").args(CARGO_ARGS)
+		.arg(toml_file)
+		.arg(source)
+		.output().unwrap();
+
+	assert_eq!(output.status.code(), Some(21));
+}
+
+/// Verify that a provider is terminated if it does not finish within its timeout.
+#[test]
+fn provider_timeout() {
+	let tmp = TempDir::default();
+
+	let source = to_file(&tmp, "source1.conf", "s1");
+
+	let toml_file = to_file(&tmp, "test.toml", &format!(r#"
+		exec = "{bin}"
+		search_path = [ "{path}/settings" ]
+
+		[secrets]
+		slow = [ "{source}" ]
+
+		[providers.slow]
+		timeout = 1
+	"#, bin = BIN_CAT, path = PROV_PATH, source = source.to_string_lossy()));
+
+	let start = std::time::Instant::now();
+	let output = Command::new("cargo").args(CARGO_ARGS)
+		.arg(toml_file)
+		.arg(source)
+		.output().unwrap();
+
+	assert!(!output.status.success(), "Slow provider was not terminated");
+	assert!(start.elapsed() < Duration::from_secs(8), "Timeout was not applied");
+	assert!(!String::from_utf8_lossy(&output.stdout).contains("s1"), "Service was started");
+}
+
+/// Test that failures exit with the exit status of their error code and that the code
+/// is logged.
+#[test]
+fn exit_status() {
+	let tmp = TempDir::default();
+
+	let source = to_file(&tmp, "source.conf", "s1");
+	let toml_file = to_file(&tmp, "test.toml", &format!(r#"
+		exec = "{bin}"
+		search_path = [ "{path}/a" ]
+
+		[secrets]
+		missing = [ "{source}" ]
+	"#, bin = BIN_CAT, path = PROV_PATH, source = source.to_string_lossy()));
+
+	let output = Command::new("cargo").args(CARGO_ARGS).arg(&toml_file).output().unwrap();
+	assert_eq!(output.status.code(), Some(20), "Missing provider has the wrong exit status.");
+	assert!(String::from_utf8_lossy(&output.stdout).contains("(TSOS-E020)"), "Error code not logged.");
+
+	let output = Command::new("cargo").args(CARGO_ARGS).arg(tmp.as_ref().join("missing.toml")).output().unwrap();
+	assert_eq!(output.status.code(), Some(10), "Missing configuration file has the wrong exit status.");
+
+	let output = Command::new("cargo").args(CARGO_ARGS).output().unwrap();
+	assert_eq!(output.status.code(), Some(1), "Missing command line parameter has the wrong exit status.");
+}
+
+/// Verify that transient failures of a provider are retried with a fresh target file
+/// and that TSoS fails if all attempts are used up.
+#[test]
+fn provider_retry() {
+	let tmp = TempDir::default();
+
+	let source = to_file(&tmp, "source.conf", "s1");
+
+	for (attempts, expected) in [ (3, Some("attempt 3\n")), (2, None) ] {
+		let counter = tmp.as_ref().join(format!("counter-{}", attempts));
+		let toml_file = to_file(&tmp, "test.toml", &format!(r#"
+			exec = "{bin}"
+			search_path = [ "{path}/settings" ]
+
+			[secrets]
+			flaky = [ "{source}" ]
+
+			[providers.flaky]
+			env = {{ COUNTER = "{counter}", FAILURES = "2" }}
+
+			[providers.flaky.retry]
+			attempts = {attempts}
+			delay = 0.01
+		"#, bin = BIN_CAT, path = PROV_PATH, source = source.to_string_lossy(), counter = counter.to_string_lossy(), attempts = attempts));
+
+		// The warnings about the retries are written to stdout too.
+		let output = Command::new("cargo").args(CARGO_ARGS)
+			.arg(toml_file)
+			.arg(&source)
+			.env("TSOS_LOG", "error")
+			.output().unwrap();
+
+		match expected {
+			Some(expected) => assert_eq!(String::from_utf8_lossy(&output.stdout), expected),
+			None => assert_eq!(output.status.code(), Some(25), "Provider did not fail after {} attempts.", attempts)
+		}
+	}
+}
+
+/// Verify that the output of a provider is logged with its name and that the last lines
+/// are added to the error message.
+#[test]
+fn provider_output() {
+	let tmp = TempDir::default();
+
+	let source = to_file(&tmp, "source.conf", "s1");
+	let toml_file = to_file(&tmp, "test.toml", &format!(r#"
+		exec = "{bin}"
+		search_path = [ "{path}/settings" ]
+
+		[secrets]
+		noisy = [ "{source}" ]
+
+		[providers.noisy]
+		output_lines = 1
+		log_stdout = true
+	"#, bin = BIN_CAT, path = PROV_PATH, source = source.to_string_lossy()));
+
+	let output = Command::new("cargo").args(CARGO_ARGS)
+		.arg(toml_file)
+		.arg(&source)
+		.env("TSOS_LOG", "info")
+		.output().unwrap();
+	let stdout = String::from_utf8_lossy(&output.stdout);
+
+	assert_eq!(output.status.code(), Some(25));
+	assert!(stdout.contains("[tsos::provider] noisy: connecting"), "stdout of the provider not logged: {}", stdout);
+	assert!(stdout.contains("[tsos::provider] noisy: first"), "stderr of the provider not logged: {}", stdout);
+	assert!(stdout.contains("failed to execute with result code 3. Last output:\n    second (TSOS-E025)"), "Last line missing within the error: {}", stdout);
+}
+
+/// Check that the signal of a terminated provider is reported and that SIGKILL fails
+/// with its own code if on_sigkill is set to oom.
+#[test]
+fn provider_signal() {
+	let tmp = TempDir::default();
+
+	let source = to_file(&tmp, "source.conf", "s1");
+	let run = |name: &str| {
+		let toml_file = to_file(&tmp, "test.toml", &format!(r#"
+			exec = "{bin}"
+			search_path = [ "{path}/settings" ]
+
+			[secrets]
+			{name} = [ "{source}" ]
+
+			[providers.terminated]
+			executable = "killed"
+			env = {{ SIGNAL = "TERM" }}
+
+			[providers.oom]
+			executable = "killed"
+			env = {{ SIGNAL = "KILL" }}
+			on_sigkill = "oom"
+		"#, bin = BIN_CAT, path = PROV_PATH, name = name, source = source.to_string_lossy()));
+
+		Command::new("cargo").args(CARGO_ARGS)
+			.arg(toml_file)
+			.arg(&source)
+			.env("TSOS_LOG", "error")
+			.output().unwrap()
+	};
+
+	let output = run("terminated");
+	let stdout = String::from_utf8_lossy(&output.stdout);
+	assert_eq!(output.status.code(), Some(26));
+	assert!(stdout.contains("terminated by signal 15 (SIGTERM). (TSOS-E026)"), "Signal missing within the error: {}", stdout);
+
+	let output = run("oom");
+	let stdout = String::from_utf8_lossy(&output.stdout);
+	assert_eq!(output.status.code(), Some(29));
+	assert!(stdout.contains("was killed by SIGKILL"), "SIGKILL not reported: {}", stdout);
+}